@@ -0,0 +1,74 @@
+// Byte interpreter against `VM::predecode` on loop-heavy programs
+// Run: cargo bench --bench interpreter
+use std::time::{Duration, Instant};
+
+use cm_vm::{ProgramBuilder, VM};
+
+const RUNS: usize = 5;
+
+// for ($0 = 0; $0 < 1_000_000; $0++) {}
+fn counting_loop() -> Vec<u8> {
+    ProgramBuilder::new()
+        .load(1, 1000)
+        .mul(1, 1, 1)
+        .label("loop")
+        .inc(0)
+        .jlt(0, 1, "loop")
+        .hlt()
+        .build()
+        .unwrap()
+}
+
+// for $0 in 0..1000 { for $2 in 0..1000 { $4 += $0 * $2 } }
+fn nested_loops() -> Vec<u8> {
+    ProgramBuilder::new()
+        .load(1, 1000)
+        .label("outer")
+        .load(2, 0)
+        .label("inner")
+        .mul(0, 2, 3)
+        .add(4, 3, 4)
+        .inc(2)
+        .jlt(2, 1, "inner")
+        .inc(0)
+        .jlt(0, 1, "outer")
+        .hlt()
+        .build()
+        .unwrap()
+}
+
+// Best of `RUNS`, decoding is part of the measured time
+fn measure(program: &[u8], predecode: bool) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut vm = VM::new();
+            vm.load_program(program.to_vec());
+            let start = Instant::now();
+            if predecode {
+                vm.predecode();
+            }
+            vm.run();
+            let elapsed = start.elapsed();
+            assert!(vm.fault().is_none(), "{:?}", vm.fault());
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    for (name, program) in [
+        ("counting_loop", counting_loop()),
+        ("nested_loops", nested_loops()),
+    ] {
+        let bytes = measure(&program, false);
+        let predecoded = measure(&program, true);
+        println!(
+            "{:<16} bytes {:>10.2?}  predecoded {:>10.2?}  x{:.2}",
+            name,
+            bytes,
+            predecoded,
+            bytes.as_secs_f64() / predecoded.as_secs_f64()
+        );
+    }
+}
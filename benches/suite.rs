@@ -0,0 +1,154 @@
+// Representative programs: instructions per second of `VM::run`, assembler throughput
+// and CMELF load time. Results are checked so a broken program can't look fast
+// Run: cargo bench --bench suite
+use std::time::{Duration, Instant};
+
+use cm_vm::{Assembler, VmConfig, VM};
+
+const RUNS: usize = 5;
+
+struct Program {
+    name: &'static str,
+    source: &'static str,
+    check: fn(&VM),
+}
+
+const PROGRAMS: &[Program] = &[
+    Program {
+        name: "counting_loop",
+        source: include_str!("programs/counting_loop.cm"),
+        check: |vm| assert_eq!(vm.register(0), 1_000_000),
+    },
+    Program {
+        name: "accumulate",
+        source: include_str!("programs/accumulate.cm"),
+        check: |vm| assert_eq!(vm.register(2), 3_000_000),
+    },
+    Program {
+        name: "fibonacci",
+        source: include_str!("programs/fibonacci.cm"),
+        check: |vm| assert_eq!(vm.register(0), 102_334_155), // fib(40)
+    },
+    Program {
+        name: "sieve",
+        source: include_str!("programs/sieve.cm"),
+        check: |vm| assert_eq!(vm.register(4), 6057), // Primes below 60000
+    },
+    Program {
+        name: "bubble_sort",
+        source: include_str!("programs/bubble_sort.cm"),
+        check: |vm| assert!(vm.heap().windows(2).all(|pair| pair[0] <= pair[1])),
+    },
+];
+
+// Best of `RUNS`
+fn best<F: FnMut() -> Duration>(mut measure: F) -> Duration {
+    (0..RUNS).map(|_| measure()).min().unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Dispatch {
+    Bytes,
+    Predecoded,
+    Superinstructions,
+}
+
+// Predecoding is part of the measured time
+fn run(program: &Program, image: &[u8], dispatch: Dispatch) -> (Duration, u64) {
+    let mut executed = 0;
+    let elapsed = best(|| {
+        let mut vm = VM::with_config(VmConfig {
+            superinstructions: dispatch == Dispatch::Superinstructions,
+            ..VmConfig::default()
+        });
+        vm.load_cmelf(image).unwrap();
+        let start = Instant::now();
+        if dispatch != Dispatch::Bytes {
+            vm.predecode();
+        }
+        vm.run();
+        let elapsed = start.elapsed();
+        assert!(vm.fault().is_none(), "{}: {:?}", program.name, vm.fault());
+        (program.check)(&vm);
+        executed = vm.instructions_executed();
+        elapsed
+    });
+    (elapsed, executed)
+}
+
+fn mips(executed: u64, elapsed: Duration) -> f64 {
+    executed as f64 / elapsed.as_secs_f64() / 1e6
+}
+
+fn bench_programs() {
+    println!(
+        "{:<14} {:>12} {:>11} {:>11} {:>11}",
+        "vm", "instructions", "bytes", "predecoded", "fused"
+    );
+    for program in PROGRAMS {
+        let image = Assembler::new().assemble(program.source).unwrap();
+        let (bytes, executed) = run(program, &image, Dispatch::Bytes);
+        let (predecoded, _) = run(program, &image, Dispatch::Predecoded);
+        let (fused, _) = run(program, &image, Dispatch::Superinstructions);
+        println!(
+            "{:<14} {:>12} {:>6.1} MIPS {:>6.1} MIPS {:>6.1} MIPS",
+            program.name,
+            executed,
+            mips(executed, bytes),
+            mips(executed, predecoded),
+            mips(executed, fused)
+        );
+    }
+}
+
+fn bench_assembler() {
+    const REPEAT: usize = 200;
+    let source = PROGRAMS
+        .iter()
+        .map(|program| program.source)
+        .collect::<Vec<_>>();
+    let lines: usize = source.iter().map(|source| source.lines().count()).sum();
+    let elapsed = best(|| {
+        let start = Instant::now();
+        for _ in 0..REPEAT {
+            for source in &source {
+                Assembler::new().assemble(source).unwrap();
+            }
+        }
+        start.elapsed()
+    });
+    println!(
+        "assembler      {:>10.0} lines/s",
+        (lines * REPEAT) as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn bench_cmelf_load() {
+    const REPEAT: u32 = 1000;
+    // 10000 instructions and 4 KiB of read-only data
+    let mut source = format!(".data\nblob: .space #{}\n.code\n", 4096);
+    for _ in 0..10_000 {
+        source.push_str("addi $0 #1 $0\n");
+    }
+    source.push_str("hlt\n");
+    let image = Assembler::new().assemble(&source).unwrap();
+    let mut vm = VM::new();
+    let elapsed = best(|| {
+        let start = Instant::now();
+        for _ in 0..REPEAT {
+            vm.load_cmelf(&image).unwrap();
+        }
+        start.elapsed()
+    });
+    println!(
+        "cmelf load     {:>10.2?} per {} bytes image",
+        elapsed / REPEAT,
+        image.len()
+    );
+}
+
+fn main() {
+    bench_programs();
+    bench_assembler();
+    bench_cmelf_load();
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+
+use crate::vm::instruction::{Opcode, OperandKind};
+use crate::vm::VM;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AssemblerError {
+    ParseError,
+    UnknownOpcode {
+        name: String,
+    },
+    SymbolAlreadyDeclared {
+        name: String,
+    },
+    UnknownLabel {
+        name: String,
+    },
+    UnknownDirective {
+        name: String,
+    },
+    MissingOperand {
+        directive: String,
+    },
+    InvalidOperand {
+        directive: String,
+    },
+    InstructionInDataSection,
+    DataOutsideDataSection {
+        directive: String,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    RecursiveInclude {
+        path: String,
+    },
+    NestedMacroDefinition,
+    UnexpectedEndm,
+    MacroWithoutEnd {
+        name: String,
+    },
+    MacroArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    RecursiveMacro {
+        name: String,
+    },
+    UnknownSymbol {
+        name: String,
+    },
+    ExpressionOverflow,
+    DivisionByZero,
+    NumberOutOfRange {
+        value: i32,
+        bits: u8, // 16 for instruction operands, 8 for `.byte`
+    },
+    InvalidRegister {
+        register: u8,
+    },
+    OperandMismatch {
+        opcode: Opcode,
+    },
+}
+
+impl AssemblerError {
+    // Text of the offending token, used to place the caret
+    pub fn token(&self) -> Option<String> {
+        match self {
+            AssemblerError::UnknownOpcode { name }
+            | AssemblerError::SymbolAlreadyDeclared { name }
+            | AssemblerError::UnknownSymbol { name }
+            | AssemblerError::MacroArgumentCount { name, .. }
+            | AssemblerError::RecursiveMacro { name } => Some(name.clone()),
+            AssemblerError::UnknownLabel { name } => Some(format!("@{}", name)),
+            AssemblerError::UnknownDirective { name } => Some(format!(".{}", name)),
+            AssemblerError::MissingOperand { directive }
+            | AssemblerError::InvalidOperand { directive }
+            | AssemblerError::DataOutsideDataSection { directive } => {
+                Some(format!(".{}", directive))
+            }
+            AssemblerError::NumberOutOfRange { value, .. } => Some(value.to_string()),
+            AssemblerError::InvalidRegister { register } => Some(format!("${}", register)),
+            AssemblerError::OperandMismatch { opcode } => {
+                Some(format!("{:?}", opcode).to_lowercase())
+            }
+            AssemblerError::DivisionByZero => Some("/".to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn help(&self) -> Option<String> {
+        let help = match self {
+            AssemblerError::ParseError => {
+                "expected `[label:] <opcode> <operands>` or `[label:] .<directive> <operands>`"
+                    .to_string()
+            }
+            AssemblerError::UnknownOpcode { .. } => {
+                "supported instructions are listed in README.md".to_string()
+            }
+            AssemblerError::SymbolAlreadyDeclared { .. } => {
+                "labels and `.equ` constants must be unique, use `.set` to redefine a constant"
+                    .to_string()
+            }
+            AssemblerError::UnknownLabel { name } => {
+                format!("declare it as `{}:` in `.code` or `.data` section", name)
+            }
+            AssemblerError::UnknownDirective { .. } => {
+                "supported directives: .data .code .asciiz .byte .word .double .space .equ .set .macro .endm .include"
+                    .to_string()
+            }
+            AssemblerError::MissingOperand { directive }
+            | AssemblerError::InvalidOperand { directive } => match directive.as_str() {
+                "asciiz" => "usage: `.asciiz \"text\"`",
+                "byte" | "word" => "usage: `.byte #1 #2 #3`, `.word #1 #2 #3`",
+                "double" => "usage: `.double #1.5 #-2.25 #3`",
+                "space" => "usage: `.space #bytes`",
+                "equ" | "set" => "usage: `.equ NAME #expression`",
+                "macro" => "usage: `.macro name arg1 arg2`",
+                "include" => "usage: `.include \"file.asm\"`",
+                _ => return None,
+            }
+            .to_string(),
+            AssemblerError::InstructionInDataSection => {
+                "add `.code` before instructions".to_string()
+            }
+            AssemblerError::DataOutsideDataSection { .. } => {
+                "add `.data` before data directives".to_string()
+            }
+            AssemblerError::IncludeNotFound { .. } => {
+                "paths are relative to the including file".to_string()
+            }
+            AssemblerError::NestedMacroDefinition | AssemblerError::MacroWithoutEnd { .. } => {
+                "close every `.macro` with `.endm`".to_string()
+            }
+            AssemblerError::UnknownSymbol { name } => format!(
+                "define it with `.equ {} #value` or declare label `{}:`",
+                name, name
+            ),
+            AssemblerError::NumberOutOfRange { bits: 8, .. } => {
+                ".byte operands are 8 bits: -128..=255".to_string()
+            }
+            AssemblerError::NumberOutOfRange { .. } => {
+                "instruction operands are 16 bits: -32768..=65535".to_string()
+            }
+            AssemblerError::OperandMismatch { opcode } => {
+                let mut usage = vec![format!("{:?}", opcode).to_lowercase()];
+                usage.extend(opcode.operands().iter().map(|kind| {
+                    match kind {
+                        OperandKind::Register => "$register",
+                        OperandKind::FloatRegister => "$float_register",
+                        OperandKind::Immediate => "#number",
+                        OperandKind::Target => "@label",
+                    }
+                    .to_string()
+                }));
+                format!("usage: {}", usage.join(" "))
+            }
+            AssemblerError::InvalidRegister { .. } => {
+                format!(
+                    "integer registers are $0..=${}, float registers $0..=${}",
+                    VM::REGISTERS - 1,
+                    VM::FLOAT_REGISTERS - 1
+                )
+            }
+            _ => return None,
+        };
+        Some(help)
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblerError::ParseError => write!(f, "Unable to parse instruction"),
+            AssemblerError::UnknownOpcode { name } => write!(f, "Unknown opcode `{}`", name),
+            AssemblerError::SymbolAlreadyDeclared { name } => {
+                write!(f, "Symbol `{}` is already declared", name)
+            }
+            AssemblerError::UnknownLabel { name } => write!(f, "Unknown label `@{}`", name),
+            AssemblerError::UnknownDirective { name } => {
+                write!(f, "Unknown directive `.{}`", name)
+            }
+            AssemblerError::MissingOperand { directive } => {
+                write!(f, "Directive `.{}` requires an operand", directive)
+            }
+            AssemblerError::InvalidOperand { directive } => {
+                write!(f, "Invalid operand for directive `.{}`", directive)
+            }
+            AssemblerError::InstructionInDataSection => {
+                write!(f, "Instruction found in `.data` section")
+            }
+            AssemblerError::DataOutsideDataSection { directive } => {
+                write!(
+                    f,
+                    "Directive `.{}` is only allowed in `.data` section",
+                    directive
+                )
+            }
+            AssemblerError::IncludeNotFound { path } => {
+                write!(f, "Unable to read included file `{}`", path)
+            }
+            AssemblerError::RecursiveInclude { path } => {
+                write!(f, "File `{}` includes itself", path)
+            }
+            AssemblerError::NestedMacroDefinition => write!(f, "Nested `.macro` definition"),
+            AssemblerError::UnexpectedEndm => write!(f, "`.endm` without `.macro`"),
+            AssemblerError::MacroWithoutEnd { name } => {
+                write!(f, "Macro `{}` is missing `.endm`", name)
+            }
+            AssemblerError::MacroArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Macro `{}` takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            AssemblerError::RecursiveMacro { name } => {
+                write!(f, "Macro `{}` expands recursively", name)
+            }
+            AssemblerError::UnknownSymbol { name } => {
+                write!(f, "Unknown constant or label `{}`", name)
+            }
+            AssemblerError::ExpressionOverflow => write!(f, "Expression overflows 32bits"),
+            AssemblerError::DivisionByZero => write!(f, "Division by zero in expression"),
+            AssemblerError::NumberOutOfRange { value, bits } => {
+                write!(f, "Number {} does not fit into {}bits operand", value, bits)
+            }
+            AssemblerError::InvalidRegister { register } => {
+                write!(f, "Register ${} does not exist", register)
+            }
+            AssemblerError::OperandMismatch { opcode } => {
+                let name = format!("{:?}", opcode).to_lowercase();
+                write!(f, "Operands do not match `{}`", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
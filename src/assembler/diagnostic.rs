@@ -0,0 +1,187 @@
+use std::fmt;
+
+use super::assembler_errors::AssemblerError;
+
+// 1-based position of an instruction or token in the source
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/*
+Renders like rustc:
+
+error: Unknown label `@nowhere`
+ --> main.asm:3:9
+  |
+3 | load $0 @nowhere
+  |         ^^^^^^^^
+  |
+  = help: declare it as `nowhere:` in `.code` or `.data` section
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AssemblerDiagnostic {
+    pub error: AssemblerError,
+    pub location: Option<SourceLocation>,
+    pub source_line: String, // Offending line as written
+    pub length: usize,       // Caret width in chars
+}
+
+impl AssemblerDiagnostic {
+    // Error without a place in the source
+    pub fn new(error: AssemblerError) -> AssemblerDiagnostic {
+        AssemblerDiagnostic {
+            error,
+            location: None,
+            source_line: String::new(),
+            length: 0,
+        }
+    }
+
+    pub fn at(
+        error: AssemblerError,
+        location: SourceLocation,
+        source_line: &str,
+        length: usize,
+    ) -> AssemblerDiagnostic {
+        AssemblerDiagnostic {
+            error,
+            location: Some(location),
+            source_line: source_line.to_string(),
+            length: length.max(1),
+        }
+    }
+
+    // Points at `needle` if it is found at or after the location column, otherwise at the rest of the line
+    pub fn at_token(
+        error: AssemblerError,
+        mut location: SourceLocation,
+        source_line: &str,
+        needle: Option<&str>,
+    ) -> AssemblerDiagnostic {
+        let start = source_line
+            .char_indices()
+            .nth(location.column.saturating_sub(1))
+            .map_or(source_line.len(), |(index, _)| index);
+        let found = needle.and_then(|needle| {
+            source_line[start..]
+                .find(needle)
+                .map(|offset| (start + offset, needle.chars().count()))
+        });
+        let length = match found {
+            Some((index, length)) => {
+                location.column = source_line[..index].chars().count() + 1;
+                length
+            }
+            None => source_line[start..].trim_end().chars().count(),
+        };
+        AssemblerDiagnostic::at(error, location, source_line, length)
+    }
+}
+
+impl fmt::Display for AssemblerDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {}", self.error)?;
+        if let Some(location) = &self.location {
+            let file = if location.file.is_empty() {
+                "<input>"
+            } else {
+                &location.file
+            };
+            let gutter = " ".repeat(location.line.to_string().len());
+            // Keep tabs so the caret lines up with the source
+            let padding: String = self
+                .source_line
+                .chars()
+                .take(location.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+
+            write!(
+                f,
+                "\n{gutter}--> {}:{}:{}",
+                file, location.line, location.column
+            )?;
+            write!(f, "\n{gutter} |")?;
+            write!(f, "\n{} | {}", location.line, self.source_line.trim_end())?;
+            write!(f, "\n{gutter} | {}{}", padding, "^".repeat(self.length))?;
+            if self.error.help().is_some() {
+                write!(f, "\n{gutter} |")?;
+            }
+        }
+        if let Some(help) = self.error.help() {
+            let gutter = self
+                .location
+                .as_ref()
+                .map_or(String::new(), |l| " ".repeat(l.line.to_string().len()));
+            write!(f, "\n{gutter} = help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssemblerDiagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_diagnostic() {
+        let location = SourceLocation {
+            file: "main.asm".to_string(),
+            line: 12,
+            column: 1,
+        };
+        let diagnostic = AssemblerDiagnostic::at_token(
+            AssemblerError::UnknownLabel {
+                name: "nowhere".to_string(),
+            },
+            location,
+            "    load $0 @nowhere",
+            Some("@nowhere"),
+        );
+        assert_eq!(diagnostic.location.as_ref().unwrap().column, 13);
+        assert_eq!(
+            diagnostic.to_string(),
+            [
+                "error: Unknown label `@nowhere`",
+                "  --> main.asm:12:13",
+                "   |",
+                "12 |     load $0 @nowhere",
+                "   |             ^^^^^^^^",
+                "   |",
+                "   = help: declare it as `nowhere:` in `.code` or `.data` section",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_render_diagnostic_without_token() {
+        let location = SourceLocation {
+            file: String::new(),
+            line: 1,
+            column: 2,
+        };
+        let diagnostic = AssemblerDiagnostic::at_token(
+            AssemblerError::ExpressionOverflow,
+            location,
+            "\tload $0 #1 ",
+            Some("missing"),
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            [
+                "error: Expression overflows 32bits",
+                " --> <input>:1:2",
+                "  |",
+                "1 | \tload $0 #1",
+                "  | \t^^^^^^^^^^",
+            ]
+            .join("\n")
+        );
+    }
+}
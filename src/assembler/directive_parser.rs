@@ -1,9 +1,11 @@
 use nom::{alpha1, alt, do_parse, named, opt, tag, types::CompleteStr, ws};
 
-use crate::assembler::register_parser::register;
-
 use super::{
-    instruction_parser::AssemblerInstruction, operand_parser::integer_operand, token::Token,
+    diagnostic::SourceLocation,
+    instruction_parser::AssemblerInstruction,
+    label_parser::{label_declaration, label_name},
+    operand_parser::operand,
+    token::Token,
 };
 
 named!(directive_declaration<CompleteStr, Token>,
@@ -13,21 +15,32 @@ named!(directive_declaration<CompleteStr, Token>,
         })
 ));
 
+// Bare name, ex. `BUF_SIZE` in `.equ BUF_SIZE #16`
+named!(identifier<CompleteStr, Token>, ws!(
+    do_parse!(
+        name: label_name >> (Token::Identifier {
+            name: name.to_string()
+        })
+    )
+));
+
 named!(directive_combined<CompleteStr, AssemblerInstruction>,
     ws!(
         do_parse!(
+            l: opt!(label_declaration) >>
             name: directive_declaration >>
-            o1: opt!(alt!(register | integer_operand)) >>
-            o2: opt!(alt!(register | integer_operand)) >>
-            o3: opt!(alt!(register | integer_operand)) >>
+            o1: opt!(alt!(operand | identifier)) >>
+            o2: opt!(alt!(operand | identifier)) >>
+            o3: opt!(alt!(operand | identifier)) >>
             (
                 AssemblerInstruction{
                     opcode: None,
                     directive: Some(name),
-                    label: None,
+                    label: l,
                     operand1: o1,
                     operand2: o2,
                     operand3: o3,
+                    location: SourceLocation::default(),
                 }
             )
         )
@@ -35,10 +48,12 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
 );
 
 /*
-<directive>
-<directive> <register | integer_operand>
-<directive> <register | integer_operand> <register | integer_operand>
-<directive> <register | integer_operand> <register | integer_operand> <register | integer_operand>
+[label:] <directive>
+[label:] <directive> <operand>
+[label:] <directive> <operand> <operand>
+[label:] <directive> <operand> <operand> <operand>
+
+<operand> := <register | integer_operand | label_usage | string_operand | identifier>
 */
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
@@ -49,9 +64,10 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
     )
 );
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::instruction::Opcode;
+    use crate::assembler::expression::{BinaryOperator, Expression};
 
     #[test]
     fn test_parse_directive_declaration() {
@@ -83,6 +99,7 @@ mod tests {
                     operand1: None,
                     operand2: None,
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -104,6 +121,7 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: None,
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -125,6 +143,7 @@ mod tests {
                     operand1: Some(Token::Number { value: 10 }),
                     operand2: None,
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -146,6 +165,7 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -167,6 +187,7 @@ mod tests {
                     operand1: Some(Token::Number { value: 10 }),
                     operand2: Some(Token::Number { value: 20 }),
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -188,6 +209,7 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: Some(Token::Number { value: 100 }),
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -209,6 +231,63 @@ mod tests {
                     operand1: Some(Token::Number { value: 10 }),
                     operand2: Some(Token::Number { value: 20 }),
                     operand3: Some(Token::Register { reg_num: 5 }),
+                    location: SourceLocation::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_directive_label_string() {
+        let result = directive(CompleteStr("hello: .asciiz \"Hello\""));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: None,
+                    directive: Some(Token::Directive {
+                        name: "asciiz".to_string()
+                    }),
+                    label: Some(Token::LabelDeclaration {
+                        name: "hello".to_string()
+                    }),
+                    operand1: Some(Token::String {
+                        value: "Hello".to_string()
+                    }),
+                    operand2: None,
+                    operand3: None,
+                    location: SourceLocation::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_directive_equ() {
+        let result = directive(CompleteStr(".equ BUF_SIZE #(4 * 4)"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: None,
+                    directive: Some(Token::Directive {
+                        name: "equ".to_string()
+                    }),
+                    label: None,
+                    operand1: Some(Token::Identifier {
+                        name: "BUF_SIZE".to_string()
+                    }),
+                    operand2: Some(Token::Expression {
+                        expr: Expression::Binary(
+                            Box::new(Expression::Number(4)),
+                            BinaryOperator::Mul,
+                            Box::new(Expression::Number(4)),
+                        )
+                    }),
+                    operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
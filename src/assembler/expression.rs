@@ -0,0 +1,120 @@
+use super::assembler_errors::AssemblerError;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// Assemble-time expression, ex. `#(BUF_SIZE * 4 + 1)`, `#label + 8`, `#-OFFSET`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expression {
+    Number(i32),
+    Symbol(String), // `.equ` / `.set` constant or label
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+}
+
+impl Expression {
+    // Replaces constant names with their definitions. Labels are kept for the second phase
+    pub fn substitute(&self, constant: &dyn Fn(&str) -> Option<Expression>) -> Expression {
+        match self {
+            Expression::Number(_) => self.clone(),
+            Expression::Symbol(name) => constant(name).unwrap_or_else(|| self.clone()),
+            Expression::Negate(inner) => Expression::Negate(Box::new(inner.substitute(constant))),
+            Expression::Binary(left, operator, right) => Expression::Binary(
+                Box::new(left.substitute(constant)),
+                *operator,
+                Box::new(right.substitute(constant)),
+            ),
+        }
+    }
+
+    pub fn evaluate(&self, symbol: &dyn Fn(&str) -> Option<i32>) -> Result<i32, AssemblerError> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Symbol(name) => {
+                symbol(name).ok_or_else(|| AssemblerError::UnknownSymbol { name: name.clone() })
+            }
+            Expression::Negate(inner) => inner
+                .evaluate(symbol)?
+                .checked_neg()
+                .ok_or(AssemblerError::ExpressionOverflow),
+            Expression::Binary(left, operator, right) => {
+                let left = left.evaluate(symbol)?;
+                let right = right.evaluate(symbol)?;
+                let result = match operator {
+                    BinaryOperator::Add => left.checked_add(right),
+                    BinaryOperator::Sub => left.checked_sub(right),
+                    BinaryOperator::Mul => left.checked_mul(right),
+                    BinaryOperator::Div | BinaryOperator::Rem if right == 0 => {
+                        return Err(AssemblerError::DivisionByZero)
+                    }
+                    BinaryOperator::Div => left.checked_div(right),
+                    BinaryOperator::Rem => left.checked_rem(right),
+                };
+                result.ok_or(AssemblerError::ExpressionOverflow)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str) -> Box<Expression> {
+        Box::new(Expression::Symbol(name.to_string()))
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // (SIZE * 4 + 1)
+        let expression = Expression::Binary(
+            Box::new(Expression::Binary(
+                symbol("SIZE"),
+                BinaryOperator::Mul,
+                Box::new(Expression::Number(4)),
+            )),
+            BinaryOperator::Add,
+            Box::new(Expression::Number(1)),
+        );
+        let lookup = |name: &str| if name == "SIZE" { Some(16) } else { None };
+        assert_eq!(expression.evaluate(&lookup), Ok(65));
+        assert_eq!(
+            Expression::Negate(symbol("MISSING")).evaluate(&lookup),
+            Err(AssemblerError::UnknownSymbol {
+                name: "MISSING".to_string()
+            })
+        );
+        assert_eq!(
+            Expression::Binary(
+                symbol("SIZE"),
+                BinaryOperator::Rem,
+                Box::new(Expression::Number(0))
+            )
+            .evaluate(&lookup),
+            Err(AssemblerError::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn test_substitute() {
+        let expression = Expression::Negate(symbol("OFFSET"));
+        let constant = |name: &str| {
+            if name == "OFFSET" {
+                Some(Expression::Number(3))
+            } else {
+                None
+            }
+        };
+        assert_eq!(
+            expression.substitute(&constant),
+            Expression::Negate(Box::new(Expression::Number(3)))
+        );
+        assert_eq!(symbol("label").substitute(&constant), *symbol("label"));
+    }
+}
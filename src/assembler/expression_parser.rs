@@ -0,0 +1,145 @@
+use nom::{
+    alt, digit, fold_many0, hex_digit, map, map_res, named, pair, preceded, tag,
+    types::CompleteStr, verify, ws,
+};
+
+use super::expression::{BinaryOperator, Expression};
+use super::label_parser::label_name;
+
+/*
+expression := term (('+' | '-') term)*
+term       := unary (('*' | '/' | '%') unary)*
+unary      := '-' unary | primary
+primary    := '0x' hex_number | number | name | '(' expression ')'
+*/
+named!(pub expression<CompleteStr, Expression>, ws!(
+    do_parse!(
+        init: term >>
+        result: fold_many0!(
+            pair!(alt!(tag!("+") | tag!("-")), term),
+            init,
+            |left, (operator, right): (CompleteStr, Expression)| {
+                let operator = match operator.0 {
+                    "+" => BinaryOperator::Add,
+                    _ => BinaryOperator::Sub,
+                };
+                Expression::Binary(Box::new(left), operator, Box::new(right))
+            }
+        ) >>
+        (result)
+    )
+));
+
+named!(term<CompleteStr, Expression>, ws!(
+    do_parse!(
+        init: unary >>
+        result: fold_many0!(
+            pair!(alt!(tag!("*") | tag!("/") | tag!("%")), unary),
+            init,
+            |left, (operator, right): (CompleteStr, Expression)| {
+                let operator = match operator.0 {
+                    "*" => BinaryOperator::Mul,
+                    "/" => BinaryOperator::Div,
+                    _ => BinaryOperator::Rem,
+                };
+                Expression::Binary(Box::new(left), operator, Box::new(right))
+            }
+        ) >>
+        (result)
+    )
+));
+
+named!(unary<CompleteStr, Expression>, ws!(
+    alt!(
+        do_parse!(tag!("-") >> inner: unary >> (Expression::Negate(Box::new(inner)))) |
+        primary
+    )
+));
+
+named!(primary<CompleteStr, Expression>, ws!(
+    alt!(
+        map_res!(
+            preceded!(tag!("0x"), hex_digit),
+            |value: CompleteStr| u32::from_str_radix(&value, 16).map(|v| Expression::Number(v as i32))
+        ) |
+        map_res!(digit, |value: CompleteStr| value.parse::<i32>().map(Expression::Number)) |
+        map!(
+            verify!(label_name, |name: CompleteStr| !name.starts_with(|c: char| c.is_ascii_digit())),
+            |name: CompleteStr| Expression::Symbol(name.to_string())
+        ) |
+        delimited!(tag!("("), expression, tag!(")"))
+    )
+));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(value: i32) -> Box<Expression> {
+        Box::new(Expression::Number(value))
+    }
+
+    #[test]
+    fn test_parse_expression_precedence() {
+        let result = expression(CompleteStr("1 + 2 * 3 - 4"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Expression::Binary(
+                    Box::new(Expression::Binary(
+                        number(1),
+                        BinaryOperator::Add,
+                        Box::new(Expression::Binary(
+                            number(2),
+                            BinaryOperator::Mul,
+                            number(3)
+                        )),
+                    )),
+                    BinaryOperator::Sub,
+                    number(4),
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_expression_names() {
+        let result = expression(CompleteStr("(BUF_SIZE % 4) / -OFFSET"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Expression::Binary(
+                    Box::new(Expression::Binary(
+                        Box::new(Expression::Symbol("BUF_SIZE".to_string())),
+                        BinaryOperator::Rem,
+                        number(4),
+                    )),
+                    BinaryOperator::Div,
+                    Box::new(Expression::Negate(Box::new(Expression::Symbol(
+                        "OFFSET".to_string()
+                    )))),
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_expression_stops_at_next_operand() {
+        let result = expression(CompleteStr("10 #20"));
+        assert_eq!(result, Ok((CompleteStr("#20"), Expression::Number(10))));
+        assert!(expression(CompleteStr("99999999999")).is_err());
+        assert!(expression(CompleteStr("(1 + 2")).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex_number() {
+        let result = expression(CompleteStr("0xFF00"));
+        assert_eq!(result, Ok((CompleteStr(""), Expression::Number(0xFF00))));
+        let result = expression(CompleteStr("0xFFFFFFFF"));
+        assert_eq!(result, Ok((CompleteStr(""), Expression::Number(-1))));
+        let result = expression(CompleteStr("0x1FFFFFFFF"));
+        assert!(!matches!(result, Ok((CompleteStr(""), _))));
+    }
+}
@@ -2,15 +2,20 @@ use log::error;
 use nom::alt;
 use nom::{do_parse, multispace, named, opt, types::CompleteStr};
 
-use crate::vm::{self, VM};
+use crate::vm;
+use crate::vm::instruction::OperandKind;
 
+use super::assembler_errors::AssemblerError;
+use super::diagnostic::SourceLocation;
 use super::directive_parser::directive;
+use super::label_parser::*;
 use super::opcode_parser::*;
 use super::operand_parser::*;
 use super::register_parser::*;
+use super::symbols::SymbolTable;
 use super::token::Token;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 #[rustfmt::skip]
 pub struct AssemblerInstruction {
     pub(crate) opcode:      Option<Token>,
@@ -19,101 +24,160 @@ pub struct AssemblerInstruction {
     pub(crate) operand1:    Option<Token>,
     pub(crate) operand2:    Option<Token>,
     pub(crate) operand3:    Option<Token>,
+    pub(crate) location:    SourceLocation, // Filled by the assembler, default when parsed alone
 }
 
 impl AssemblerInstruction {
-    pub fn to_hex(&self) -> Vec<String> {
-        let mut results: Vec<String> = vec![];
+    pub fn to_hex(&self, symbols: &SymbolTable) -> Vec<String> {
+        self.to_bytes(symbols)
+            .iter()
+            .map(|byte| format!("{:02x?}", byte))
+            .collect()
+    }
+
+    // From AssemblerInstruction to bytes. Directives and labels produce no bytes
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+        let mut results = vec![];
         match self.opcode {
             Some(Token::Op { code }) => {
-                let bytes = code as u8;
-                let hex = format!("{:02x?}", bytes);
-                results.push(hex);
-            }
-            _ => {
-                error!("Non-opcode found in opcode field");
-                std::process::exit(1);
+                results.push(code as u8);
             }
+            _ => return results,
         };
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                self.extract_hex_operand(token, &mut results);
-            }
+        for token in self.operands() {
+            self.extract_operand(token, symbols, &mut results)
         }
 
         results
     }
 
-    // From AssemblerInstruction to bytes
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
+    // Size of the encoded instruction in bytes, from the opcode. Known before labels are resolved
+    pub fn byte_len(&self) -> u32 {
         match self.opcode {
-            Some(Token::Op { code }) => {
-                results.push(code as u8);
-            }
-            _ => {
-                error!("Non-opcode found in opcode field");
-                std::process::exit(1);
-            }
-        };
+            Some(Token::Op { code }) => code.byte_len() as u32,
+            _ => 0,
+        }
+    }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                self.extract_operand(token, &mut results)
+    // Operand count and kinds against `Opcode::operands`, then register numbers against
+    // their bank, the same checks as `ProgramBuilder::emit`
+    pub fn check_operands(&self) -> Result<(), AssemblerError> {
+        let Some(Token::Op { code }) = self.opcode else {
+            return Ok(());
+        };
+        let kinds = code.operands();
+        let mismatch = AssemblerError::OperandMismatch { opcode: code };
+        if self.operands().count() != kinds.len() {
+            return Err(mismatch);
+        }
+        for (kind, token) in kinds.iter().zip(self.operands()) {
+            match (kind, token) {
+                (
+                    OperandKind::Register | OperandKind::FloatRegister,
+                    Token::Register { reg_num },
+                ) => {
+                    if *reg_num as usize >= kind.register_count() {
+                        return Err(AssemblerError::InvalidRegister { register: *reg_num });
+                    }
+                }
+                (
+                    OperandKind::Immediate | OperandKind::Target,
+                    Token::Number { .. }
+                    | Token::LabelUsage { .. }
+                    | Token::String { .. }
+                    | Token::Float { .. }
+                    | Token::Expression { .. },
+                ) => {}
+                _ => return Err(mismatch),
             }
         }
+        Ok(())
+    }
 
-        results
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
     }
 
-    // Token -> hex
-    fn extract_hex_operand(&self, token: &Token, hex_results: &mut Vec<String>) {
-        match token {
-            Token::Register { reg_num } => {
-                let hex = format!("{:02x?}", *reg_num);
-                hex_results.push(hex);
-            }
-            Token::Number { value } => {
-                let converted = *value as u16;
-                let hex1 = format!("{:02x?}", converted as u8);
-                let hex2 = format!("{:02x?}", (converted >> VM::SIZE) as u8);
+    pub fn is_directive(&self) -> bool {
+        self.directive.is_some()
+    }
 
-                hex_results.push(hex2);
-                hex_results.push(hex1);
-            }
-            _ => {
-                error!("Opcode found in operand field");
-                std::process::exit(1);
-            }
-        };
+    pub fn label_name(&self) -> Option<&str> {
+        match &self.label {
+            Some(Token::LabelDeclaration { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn directive_name(&self) -> Option<&str> {
+        match &self.directive {
+            Some(Token::Directive { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+    }
+
+    pub fn operands_mut(&mut self) -> impl Iterator<Item = &mut Token> {
+        [&mut self.operand1, &mut self.operand2, &mut self.operand3]
+            .into_iter()
+            .flatten()
     }
 
     // Token -> u8
-    fn extract_operand(&self, token: &Token, results: &mut Vec<u8>) {
+    fn extract_operand(&self, token: &Token, symbols: &SymbolTable, results: &mut Vec<u8>) {
         match token {
             Token::Register { reg_num } => {
                 results.push(*reg_num);
             }
             Token::Number { value } => {
-                let converted = *value as u16;
-                let byte1 = converted as u8;
-                // Invert to vm::VM::next_16_bits
-                let byte2 = (converted >> vm::VM::SIZE) as u8; // Take 8 higher
-                results.push(byte2);
-                results.push(byte1); // Note: Convert here u16->u18 just throws 8 higher bits and take lower
+                Self::push_16_bits(*value as u16, results);
             }
+            Token::LabelUsage { name } => match symbols.symbol_value(name) {
+                Some(offset) => Self::push_16_bits(offset as u16, results),
+                None => {
+                    error!("No value found for label `@{}`", name);
+                }
+            },
+            Token::String { value } => match symbols.string_offset(value) {
+                Some(offset) => Self::push_16_bits(offset as u16, results),
+                None => {
+                    error!("String {:?} is not interned in read-only data", value);
+                }
+            },
+            Token::Float { bits } => match symbols.float_offset(*bits) {
+                Some(offset) => Self::push_16_bits(offset as u16, results),
+                None => {
+                    error!(
+                        "Float {} is not interned in read-only data",
+                        f64::from_bits(*bits)
+                    );
+                }
+            },
             _ => {
                 error!("Opcode found in operand field");
                 std::process::exit(1);
             }
         };
     }
+
+    fn push_16_bits(converted: u16, results: &mut Vec<u8>) {
+        let byte1 = converted as u8;
+        // Invert to vm::VM::next_16_bits
+        let byte2 = (converted >> vm::VM::SIZE) as u8; // Take 8 higher
+        results.push(byte2);
+        results.push(byte1); // Note: Convert here u16->u18 just throws 8 higher bits and take lower
+    }
 }
 
 named!(pub instruction<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        ins: alt!(instruction_pure | directive) >>
+        ins: alt!(instruction_pure | directive | label_only) >>
         (
             ins
         )
@@ -121,33 +185,60 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 );
 
 /*
-<opcode>
-<opcode> <register | integer_operand>
-<opcode> <register | integer_operand> <register | integer_operand>
-<opcode> <register | integer_operand> <register | integer_operand> <register | integer_operand>
+[label:] <opcode>
+[label:] <opcode> <register>
+[label:] <opcode> <register> <operand>
+[label:] <opcode> <register> <operand> <operand>
+
+operand := register | float_operand | integer_operand | label_usage | string_operand
+
+String operand encodes as 16bits offset of its NUL terminated copy in read-only data
+Float operand encodes as 16bits offset of its 8 bytes big-endian copy in read-only data
 */
 named!(pub instruction_pure<CompleteStr, AssemblerInstruction>,
     do_parse!(
+        l:  opt!(label_declaration) >>
         o:  opcode >>
         r1: opt!(register) >>
-        r2: opt!(alt!(register | integer_operand)) >>
-        r3: opt!(alt!(register | integer_operand)) >>
+        r2: opt!(operand) >>
+        r3: opt!(operand) >>
         opt!(multispace) >>
         (
             AssemblerInstruction{
                 opcode: Some(o),
                 directive: None,
-                label: None,
+                label: l,
                 operand1: r1,
                 operand2: r2,
-                operand3: r3
+                operand3: r3,
+                location: SourceLocation::default(),
             }
         )
     )
 );
 
+// `loop:` on its own line marks the next instruction
+named!(label_only<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        l: label_declaration >>
+        (
+            AssemblerInstruction{
+                opcode: None,
+                directive: None,
+                label: Some(l),
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                location: SourceLocation::default(),
+            }
+        )
+    )
+);
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::symbols::{Symbol, SymbolType};
     use crate::vm::instruction::Opcode;
 
     #[test]
@@ -163,7 +254,8 @@ mod tests {
                     label: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Number { value: 100 }),
-                    operand3: None
+                    operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -183,6 +275,7 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 5 }),
                     operand3: Some(Token::Register { reg_num: 2 }),
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -202,6 +295,7 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 1 }),
                     operand2: None,
                     operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -220,7 +314,8 @@ mod tests {
                     label: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
-                    operand3: Some(Token::Register { reg_num: 2 })
+                    operand3: Some(Token::Register { reg_num: 2 }),
+                    location: SourceLocation::default(),
                 }
             ))
         );
@@ -242,9 +337,80 @@ mod tests {
                     label: None,
                     operand1: None,
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    location: SourceLocation::default(),
                 }
             ))
         );
     }
+
+    #[test]
+    fn test_parse_instruction_label_usage() {
+        let result = instruction(CompleteStr("start: load $0 @msg"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: Some(Token::Op { code: Opcode::LOAD }),
+                    directive: None,
+                    label: Some(Token::LabelDeclaration {
+                        name: "start".to_string()
+                    }),
+                    operand1: Some(Token::Register { reg_num: 0 }),
+                    operand2: Some(Token::LabelUsage {
+                        name: "msg".to_string()
+                    }),
+                    operand3: None,
+                    location: SourceLocation::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_label_only() {
+        let result = instruction(CompleteStr("loop:"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: None,
+                    directive: None,
+                    label: Some(Token::LabelDeclaration {
+                        name: "loop".to_string()
+                    }),
+                    operand1: None,
+                    operand2: None,
+                    operand3: None,
+                    location: SourceLocation::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_instruction_to_bytes_with_label() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("msg".to_string(), SymbolType::Data, 258));
+        let (_, instruction) = instruction(CompleteStr("load $1 @msg")).unwrap();
+        assert_eq!(instruction.byte_len(), 4);
+        assert_eq!(instruction.to_bytes(&symbols), vec![0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_instruction_to_bytes_with_string() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_string("Hi\n".to_string(), 7);
+        let (_, instruction) = instruction(CompleteStr("load $2 \"Hi\\n\"")).unwrap();
+        assert_eq!(
+            instruction.operand2,
+            Some(Token::String {
+                value: "Hi\n".to_string()
+            })
+        );
+        assert_eq!(instruction.byte_len(), 4);
+        assert_eq!(instruction.to_bytes(&symbols), vec![0, 2, 0, 7]);
+    }
 }
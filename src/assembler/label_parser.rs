@@ -0,0 +1,64 @@
+use nom::{named, tag, take_while1, types::CompleteStr, ws};
+
+use super::token::Token;
+
+named!(pub label_name<CompleteStr, CompleteStr>,
+    take_while1!(|c: char| c.is_alphanumeric() || c == '_')
+);
+
+// `loop:`
+named!(pub label_declaration<CompleteStr, Token>, ws!(
+    do_parse!(
+        name: label_name >> tag!(":") >> (Token::LabelDeclaration {
+            name: name.to_string()
+        })
+    )
+));
+
+// `@loop`
+named!(pub label_usage<CompleteStr, Token>, ws!(
+    do_parse!(
+        tag!("@") >> name: label_name >> (Token::LabelUsage {
+            name: name.to_string()
+        })
+    )
+));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration(CompleteStr("test_1:"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::LabelDeclaration {
+                name: "test_1".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = label_declaration(CompleteStr("test"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage(CompleteStr("@test"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::LabelUsage {
+                name: "test".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = label_usage(CompleteStr("test"));
+        assert!(result.is_err());
+    }
+}
@@ -1,71 +1,510 @@
+use std::path::Path;
+
 use log::debug;
-use nom::{do_parse, many1, named, types::CompleteStr};
+use nom::{do_parse, many1, named_attr, types::CompleteStr};
+
+use assembler_errors::AssemblerError;
+use diagnostic::{AssemblerDiagnostic, SourceLocation};
+use expression::Expression;
+use instruction_parser::instruction;
+use preprocessor::{Preprocessor, SourceLine};
+use symbols::{Symbol, SymbolTable, SymbolType};
+use token::Token;
 
-use instruction_parser::{instruction, AssemblerInstruction};
+use crate::cmelf::Cmelf;
+use crate::vm::instruction::Opcode;
 
+pub mod assembler_errors;
+pub mod diagnostic;
 mod directive_parser;
+mod expression;
+mod expression_parser;
 mod instruction_parser;
+mod label_parser;
 mod opcode_parser;
 mod operand_parser;
+mod optimizer;
+mod preprocessor;
 mod register_parser;
+mod string_parser;
+pub mod symbols;
 mod token;
 
+pub use instruction_parser::AssemblerInstruction;
+
 #[derive(Debug, PartialEq, Eq)]
-pub struct Assembler {
+pub struct Program {
     instructions: Vec<AssemblerInstruction>,
 }
 
-impl Assembler {
-    // TODO
-    #[allow(dead_code)]
-    pub fn to_hex(&self) -> Vec<&str> {
-        todo!()
-    }
-
-    #[allow(dead_code)]
+impl Program {
     pub fn to_bytes(&self) -> Vec<u8> {
+        let symbols = SymbolTable::new();
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            program.append(&mut instruction.to_bytes(&symbols));
         }
         program
     }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AssemblerSection {
+    Data, // `.data` Read-only data. Labels point into `ro_data`
+    Code, // `.code` Instructions. Labels point into the program. Default section
+}
+
+/// Two phase assembler: phase 1 collects labels, constants and read-only data, phase 2 emits code
+#[derive(Debug)]
+pub struct Assembler {
+    symbols: SymbolTable,
+    ro: Vec<u8>,       // Read-only data section being built
+    bytecode: Vec<u8>, // Code section being built
+    section: AssemblerSection,
+    lines: Vec<SourceLine>, // Source being assembled, for diagnostics
+    errors: Vec<AssemblerDiagnostic>,
+    optimize: bool, // Run the peephole optimizer before resolving labels
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            symbols: SymbolTable::new(),
+            ro: vec![],
+            bytecode: vec![],
+            section: AssemblerSection::Code,
+            lines: vec![],
+            errors: vec![],
+            optimize: false,
+        }
+    }
+
+    /// Shrinks the program with the peephole optimizer: jumps to the next instruction,
+    /// constant adds, unreachable code and `load`+`jmp` branches. Off by default
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Parses one line without assembling it. Labels stay unresolved
+    pub fn parse_instruction(
+        input_instruction: &str,
+    ) -> Result<AssemblerInstruction, AssemblerDiagnostic> {
+        Assembler::parse_line(&SourceLine {
+            file: String::new(),
+            line: 1,
+            text: input_instruction.to_string(),
+        })
+    }
 
-    pub fn parse_instruction(input_instruction: &str) -> Result<AssemblerInstruction, ()> {
-        let parsed = instruction(CompleteStr(input_instruction));
+    fn parse_line(source: &SourceLine) -> Result<AssemblerInstruction, AssemblerDiagnostic> {
+        let location = source.location();
+        let text = source.text.trim();
+        let failed_at = match instruction(CompleteStr(text)) {
+            Ok((rest, mut instruction)) if rest.is_empty() => {
+                debug!("Parsed {:?}", instruction);
+                instruction.location = location.clone();
+                if let Some(Token::Op { code: Opcode::IGL }) = instruction.opcode {
+                    let name = text
+                        .split_whitespace()
+                        .find(|word| !word.ends_with(':'))
+                        .unwrap_or_default();
+                    return Err(AssemblerDiagnostic::at_token(
+                        AssemblerError::UnknownOpcode {
+                            name: name.to_string(),
+                        },
+                        location,
+                        &source.text,
+                        Some(name),
+                    ));
+                }
+                return Ok(instruction);
+            }
+            Ok((rest, _)) => text.len() - rest.len(),
+            Err(_) => 0,
+        };
 
-        match parsed {
-            Ok(instruction_result) => {
-                debug!("Parsed {:?}", instruction_result.1);
-                if instruction_result.0.is_empty() {
-                    Ok(instruction_result.1)
+        // Caret under the first token the parser could not consume
+        let rest = &text[failed_at..];
+        let skipped = rest.len() - rest.trim_start().len();
+        let token = rest.split_whitespace().next().unwrap_or_default();
+        let column = location.column + text[..failed_at + skipped].chars().count();
+        Err(AssemblerDiagnostic::at(
+            AssemblerError::ParseError,
+            SourceLocation { column, ..location },
+            &source.text,
+            token.chars().count(),
+        ))
+    }
+
+    /// One instruction per line. Empty lines are skipped
+    pub fn parse_program(input_program: &[&str]) -> Result<Program, Vec<AssemblerDiagnostic>> {
+        let lines: Vec<SourceLine> = input_program
+            .iter()
+            .enumerate()
+            .map(|(index, text)| SourceLine {
+                file: String::new(),
+                line: index + 1,
+                text: text.to_string(),
+            })
+            .collect();
+        Assembler::parse_source(&lines)
+    }
+
+    fn parse_source(lines: &[SourceLine]) -> Result<Program, Vec<AssemblerDiagnostic>> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for source in lines.iter().filter(|source| !source.text.trim().is_empty()) {
+            match Assembler::parse_line(source) {
+                Ok(instruction) => instructions.push(instruction),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Source -> CMELF image (header + read-only data + code)
+    /// `.include` paths are relative to the current directory
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerDiagnostic>> {
+        let lines = Preprocessor::new().process(raw, "")?;
+        self.assemble_lines(&lines)
+    }
+
+    /// `.include` paths are relative to the file
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, Vec<AssemblerDiagnostic>> {
+        let lines = Preprocessor::new().process_file(path)?;
+        self.assemble_lines(&lines)
+    }
+
+    fn assemble_lines(
+        &mut self,
+        lines: &[SourceLine],
+    ) -> Result<Vec<u8>, Vec<AssemblerDiagnostic>> {
+        // Nothing carries over from the previous source, only the settings
+        *self = Assembler {
+            optimize: self.optimize,
+            ..Assembler::new()
+        };
+        let mut program = Assembler::parse_source(lines)?;
+        self.lines = lines.to_vec();
+        for instruction in &program.instructions {
+            if let Err(e) = instruction.check_operands() {
+                self.error(e, instruction);
+            }
+        }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        if self.optimize {
+            program.instructions = optimizer::optimize(program.instructions);
+        }
+
+        self.process_first_phase(&mut program);
+        if self.errors.is_empty() {
+            self.process_second_phase(&program);
+        }
+
+        if self.errors.is_empty() {
+            let cmelf = Cmelf::new(self.ro.clone(), self.bytecode.clone());
+            Ok(cmelf.to_bytes())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn error(&mut self, error: AssemblerError, instruction: &AssemblerInstruction) {
+        let location = instruction.location.clone();
+        let needle = error.token();
+        // Macro expansions share the location of the macro body line
+        let candidates: Vec<&SourceLine> = self
+            .lines
+            .iter()
+            .filter(|source| source.file == location.file && source.line == location.line)
+            .collect();
+        let text = candidates
+            .iter()
+            .find(|source| {
+                needle
+                    .as_ref()
+                    .is_some_and(|n| source.text.contains(n.as_str()))
+            })
+            .or(candidates.first())
+            .map_or("", |source| source.text.as_str());
+        self.errors.push(AssemblerDiagnostic::at_token(
+            error,
+            location,
+            text,
+            needle.as_deref(),
+        ));
+    }
+
+    // Resolve label offsets, substitute constants, fill read-only data
+    fn process_first_phase(&mut self, program: &mut Program) {
+        let mut code_offset = 0;
+        for instruction in program.instructions.iter_mut() {
+            self.substitute_constants(instruction);
+            if let Some(name) = instruction.directive_name() {
+                if self.switch_section(name) {
+                    continue;
+                }
+            }
+
+            if let Some(name) = instruction.label_name() {
+                let (symbol_type, offset) = match self.section {
+                    AssemblerSection::Code => (SymbolType::Code, code_offset),
+                    AssemblerSection::Data => (SymbolType::Data, self.ro.len() as u32),
+                };
+                if self.symbols.has_symbol(name) || self.symbols.constant(name).is_some() {
+                    self.error(
+                        AssemblerError::SymbolAlreadyDeclared {
+                            name: name.to_string(),
+                        },
+                        instruction,
+                    );
                 } else {
-                    Err(())
+                    self.symbols
+                        .add_symbol(Symbol::new(name.to_string(), symbol_type, offset));
+                }
+            }
+
+            if matches!(instruction.directive_name(), Some("equ" | "set")) {
+                self.process_constant(instruction);
+            } else if instruction.is_directive() {
+                self.process_data_directive(instruction);
+            } else if instruction.is_opcode() {
+                if self.section == AssemblerSection::Data {
+                    self.error(AssemblerError::InstructionInDataSection, instruction);
+                }
+                self.intern_literals(instruction);
+                code_offset += instruction.byte_len();
+            }
+        }
+    }
+
+    // String and float operands of instructions are stored once in read-only data
+    fn intern_literals(&mut self, instruction: &AssemblerInstruction) {
+        for token in instruction.operands() {
+            match token {
+                Token::String { value } if self.symbols.string_offset(value).is_none() => {
+                    self.symbols.add_string(value.clone(), self.ro.len() as u32);
+                    self.ro.extend_from_slice(value.as_bytes());
+                    self.ro.push(0);
+                }
+                Token::Float { bits } if self.symbols.float_offset(*bits).is_none() => {
+                    self.symbols.add_float(*bits, self.ro.len() as u32);
+                    self.ro.extend_from_slice(&bits.to_be_bytes());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Constants defined so far are replaced by their definitions (`.set` takes the latest one)
+    fn substitute_constants(&self, instruction: &mut AssemblerInstruction) {
+        for token in instruction.operands_mut() {
+            if let Token::Expression { expr } = token {
+                *expr = expr.substitute(&|name| self.symbols.constant(name));
+            }
+        }
+    }
+
+    /*
+    .equ NAME #expression   Define constant once
+    .set NAME #expression   Define or redefine constant
+    */
+    fn process_constant(&mut self, instruction: &AssemblerInstruction) {
+        let directive = instruction.directive_name().unwrap_or_default().to_string();
+        let value = match &instruction.operand2 {
+            Some(Token::Number { value }) => Expression::Number(*value),
+            Some(Token::Expression { expr }) => expr.clone(),
+            None => {
+                self.error(AssemblerError::MissingOperand { directive }, instruction);
+                return;
+            }
+            _ => {
+                self.error(AssemblerError::InvalidOperand { directive }, instruction);
+                return;
+            }
+        };
+        let name = match &instruction.operand1 {
+            Some(Token::Identifier { name }) if instruction.operand3.is_none() => name.clone(),
+            _ => {
+                self.error(AssemblerError::InvalidOperand { directive }, instruction);
+                return;
+            }
+        };
+
+        let defined = self.symbols.has_symbol(&name) || self.symbols.constant(&name).is_some();
+        if defined && (directive == "equ" || self.symbols.has_symbol(&name)) {
+            self.error(AssemblerError::SymbolAlreadyDeclared { name }, instruction);
+        } else {
+            self.symbols.set_constant(name, value);
+        }
+    }
+
+    // Labels and constants known at this point
+    fn symbol_value(&self, name: &str) -> Option<i32> {
+        let label = |name: &str| self.symbols.symbol_value(name).map(|offset| offset as i32);
+        label(name).or_else(|| self.symbols.constant(name)?.evaluate(&label).ok())
+    }
+
+    fn number_value(&self, token: &Token) -> Option<Result<i32, AssemblerError>> {
+        match token {
+            Token::Number { value } => Some(Ok(*value)),
+            Token::Expression { expr } => Some(expr.evaluate(&|name| self.symbol_value(name))),
+            _ => None,
+        }
+    }
+
+    // Emit bytecode with resolved labels and evaluated expressions
+    fn process_second_phase(&mut self, program: &Program) {
+        for instruction in program.instructions.iter().filter(|i| i.is_opcode()) {
+            let errors = self.errors.len();
+            let mut resolved = instruction.clone();
+            for token in resolved.operands_mut() {
+                let value = match self.number_value(token) {
+                    Some(Ok(value)) => value,
+                    Some(Err(e)) => {
+                        self.error(e, instruction);
+                        continue;
+                    }
+                    None => {
+                        if let Token::LabelUsage { name } = token {
+                            if !self.symbols.has_symbol(name) {
+                                self.error(
+                                    AssemblerError::UnknownLabel { name: name.clone() },
+                                    instruction,
+                                );
+                            }
+                        }
+                        continue;
+                    }
+                };
+                if !(i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+                    self.error(
+                        AssemblerError::NumberOutOfRange { value, bits: 16 },
+                        instruction,
+                    );
                 }
+                *token = Token::Number { value };
+            }
+            if self.errors.len() == errors {
+                self.bytecode.append(&mut resolved.to_bytes(&self.symbols));
             }
-            Result::Err(_) => Err(()),
         }
     }
 
-    // TODO
-    #[allow(dead_code)]
-    #[allow(unused)]
-    pub fn parse_program(input_program: &[&str]) -> Assembler {
-        todo!();
+    fn switch_section(&mut self, name: &str) -> bool {
+        match name {
+            "data" => self.section = AssemblerSection::Data,
+            "code" => self.section = AssemblerSection::Code,
+            _ => return false,
+        }
+        true
+    }
+
+    /*
+    .asciiz "text"    NUL terminated string
+    .byte #1 #2 #3    Up to 3 bytes
+    .word #1 #2 #3    Up to 3 32bits words (big endian)
+    .double #1.5 #2   Up to 3 f64 (big endian), integers are converted
+    .space #100       Zeroed bytes
+    */
+    fn process_data_directive(&mut self, instruction: &AssemblerInstruction) {
+        let name = instruction.directive_name().unwrap_or_default().to_string();
+        if !matches!(
+            name.as_str(),
+            "asciiz" | "byte" | "word" | "double" | "space"
+        ) {
+            self.error(AssemblerError::UnknownDirective { name }, instruction);
+            return;
+        }
+        if self.section != AssemblerSection::Data {
+            self.error(
+                AssemblerError::DataOutsideDataSection { directive: name },
+                instruction,
+            );
+            return;
+        }
+        if instruction.operands().next().is_none() {
+            self.error(
+                AssemblerError::MissingOperand { directive: name },
+                instruction,
+            );
+            return;
+        }
+
+        for token in instruction.operands() {
+            let number = match self.number_value(token) {
+                Some(Ok(value)) => Some(value),
+                Some(Err(e)) => {
+                    self.error(e, instruction);
+                    continue;
+                }
+                None => None,
+            };
+            match (name.as_str(), token, number) {
+                ("asciiz", Token::String { value }, _) => {
+                    self.ro.extend_from_slice(value.as_bytes());
+                    self.ro.push(0);
+                }
+                ("byte", _, Some(value)) if (i8::MIN as i32..=u8::MAX as i32).contains(&value) => {
+                    self.ro.push(value as u8)
+                }
+                ("byte", _, Some(value)) => self.error(
+                    AssemblerError::NumberOutOfRange { value, bits: 8 },
+                    instruction,
+                ),
+                ("word", _, Some(value)) => self.ro.extend_from_slice(&value.to_be_bytes()),
+                ("double", Token::Float { bits }, _) => {
+                    self.ro.extend_from_slice(&bits.to_be_bytes())
+                }
+                ("double", _, Some(value)) => self
+                    .ro
+                    .extend_from_slice(&(value as f64).to_bits().to_be_bytes()),
+                ("space", _, Some(value)) if value >= 0 => {
+                    let new_len = self.ro.len() + value as usize;
+                    self.ro.resize(new_len, 0);
+                }
+                _ => {
+                    self.error(
+                        AssemblerError::InvalidOperand {
+                            directive: name.clone(),
+                        },
+                        instruction,
+                    );
+                }
+            }
+        }
     }
 }
 
 // Root of parsing. Private
-// TODO: Not finished yet
-named!(program<CompleteStr, Assembler>, do_parse!(
-    instructions: many1!(instruction) >> (Assembler {
+named_attr!(#[allow(dead_code)], program<CompleteStr, Program>, do_parse!(
+    instructions: many1!(instruction) >> (Program {
         instructions
     })
 ));
 
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
+    fn error_kinds(diagnostics: Vec<AssemblerDiagnostic>) -> Vec<AssemblerError> {
+        diagnostics.into_iter().map(|d| d.error).collect()
+    }
+
     #[test]
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100\n"));
@@ -83,4 +522,474 @@ mod tests {
         let bytecode = program.to_bytes();
         assert_eq!(bytecode.len(), 4);
     }
+
+    #[test]
+    fn test_assemble_data_section() {
+        let source = "
+            .data
+            hello: .asciiz \"Hi\"
+            table: .byte #1 #2 #3
+            big:   .word #258
+            buf:   .space #2
+            .code
+            load $0 @table
+            loop: load $1 @big
+            load $2 @loop
+            hlt
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let cmelf = Cmelf::from_bytes(&bytes).unwrap();
+        assert_eq!(cmelf.ro_data, vec![72, 105, 0, 1, 2, 3, 0, 0, 1, 2, 0, 0]);
+        assert_eq!(cmelf.code, vec![0, 0, 0, 3, 0, 1, 0, 6, 0, 2, 0, 4, 5]);
+        // Same assembler, same image
+        assert_eq!(assembler.assemble(source).unwrap(), bytes);
+
+        let bytes = assembler.assemble(".data\n.byte #-128 #255").unwrap();
+        assert_eq!(Cmelf::from_bytes(&bytes).unwrap().ro_data, vec![128, 255]);
+        let errors = error_kinds(assembler.assemble(".data\n.byte #256 #-129").unwrap_err());
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::NumberOutOfRange {
+                    value: 256,
+                    bits: 8
+                },
+                AssemblerError::NumberOutOfRange {
+                    value: -129,
+                    bits: 8
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_string_operands() {
+        let source = "
+            .data
+            table: .byte #1
+            .code
+            load $0 \"Hi\\n\"
+            load $1 @table
+            load $2 \"Hi\\n\"
+            load $3 \"\\\"ok\\\"\"
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let cmelf = Cmelf::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            cmelf.ro_data,
+            vec![1, b'H', b'i', b'\n', 0, b'"', b'o', b'k', b'"', 0]
+        );
+        assert_eq!(
+            cmelf.code,
+            vec![0, 0, 0, 1, 0, 1, 0, 0, 0, 2, 0, 1, 0, 3, 0, 5]
+        );
+    }
+
+    #[test]
+    fn test_assemble_macro() {
+        let source = "
+            .macro count_down reg
+            loop: load $5 #1
+            sub \\reg $5 \\reg
+            load $5 #0
+            gt \\reg $5 $5
+            load $5 @loop
+            jeq $5
+            .endm
+            load $0 #3
+            count_down $0
+            load $1 #2
+            count_down $1
+            hlt
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), 0);
+        assert_eq!(vm.register(1), 0);
+    }
+
+    #[test]
+    fn test_assemble_branches() {
+        // while ($0 < $1) { $2 += $0; $0 += 1 }
+        let source = "
+            load $0 #0
+            load $1 #5
+            load $3 #1
+            loop: jgtq $0 $1 @end
+            add $2 $0 $2
+            add $0 $3 $0
+            load $4 #loop
+            jmp $4
+            end: eq $2 $1 $5
+            load $4 #(skip - after)
+            jneqf $4
+            after: load $2 #0
+            skip: hlt
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let code = &bytes[crate::cmelf::CMELF_HEADER_LENGTH..];
+        assert_eq!(&code[12..17], &[Opcode::JGTQ as u8, 0, 1, 0, 31]);
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), 5);
+        assert_eq!(vm.register(2), 10);
+    }
+
+    #[test]
+    fn test_assemble_bitwise() {
+        // Pack two bytes into one word and unpack the high one back
+        let source = "
+            .equ MASK #0xFF
+            load $0 #0x12
+            load $1 #52
+            shli $0 #8 $2
+            or $2 $1 $2
+            sari $2 #8 $3
+            andi $3 #MASK $3
+            not $3 $4
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.register(2), 0x1234);
+        assert_eq!(vm.register(3), 0x12);
+        assert_eq!(vm.register(4), !0x12);
+    }
+
+    #[test]
+    fn test_assemble_counter_loop() {
+        let source = "
+            load $1 #3
+            loop: inc $0
+            addi $2 #-2 $2
+            jlt $0 $1 @loop
+            mov $2 $3
+            dec $3
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), 3);
+        assert_eq!(vm.register(2), -6);
+        assert_eq!(vm.register(3), -7);
+    }
+
+    #[test]
+    fn test_assemble_floats() {
+        // r * r * scale
+        let source = "
+            .data
+            scale: .double #2.5 #2
+            .code
+            loadf $0 @scale
+            load $0 #3
+            itof $0 $1
+            mulf $1 $1 $2
+            mulf $2 $0 $2
+            loadf $3 #0.5
+            loadf $4 #0.5
+            eqf $3 $4
+            ftoi $2 $5
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        assert_eq!(vm.ro_data().len(), 3 * 8); // `#0.5` is interned once
+        assert_eq!(&vm.ro_data()[8..16], &2.0f64.to_be_bytes());
+        vm.run();
+        assert_eq!(vm.float_register(2), 22.5);
+        assert!(vm.flags().equal);
+        assert_eq!(vm.register(5), 22);
+    }
+
+    #[test]
+    fn test_assemble_syscalls() {
+        let source = "
+            .equ PRINT_INT #1
+            .equ PRINT_STRING #3
+            .equ READ_INT #5
+            syscall #READ_INT
+            mov $0 $2
+            load $0 \"square: \"
+            syscall #PRINT_STRING
+            mul $2 $2 $0
+            syscall #PRINT_INT
+            syscall #0
+        ";
+        let io = crate::vm::io::BufferedIo::new("12\n");
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let mut vm = crate::vm::VM::new();
+        vm.set_io(Box::new(io.clone()));
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(io.output(), "square: 144");
+        assert_eq!(vm.exit_code(), Some(144));
+    }
+
+    #[test]
+    fn test_assemble_native() {
+        fn double(vm: &mut crate::vm::VM) -> Result<(), crate::vm::fault::VmFault> {
+            vm.set_register(0, vm.register(0) * 2);
+            Ok(())
+        }
+
+        let bytes = Assembler::new()
+            .assemble(".equ DOUBLE #7\nload $0 #21\nnative #DOUBLE\nhlt")
+            .unwrap();
+        assert_eq!(&bytes[bytes.len() - 4..], &[69, 0, 7, 5]);
+        let mut vm = crate::vm::VM::new();
+        vm.register_native(7, double);
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        assert_eq!(vm.register(0), 42);
+    }
+
+    #[test]
+    fn test_assemble_constants() {
+        let source = "
+            .equ BUF_SIZE #16
+            .set STEP #2
+            .data
+            buf: .space #BUF_SIZE / 4
+            after: .byte #-1 #(STEP * 3)
+            .code
+            load $0 #(BUF_SIZE * 4 + 1)
+            load $1 #after + 8
+            .set STEP #STEP + 1
+            load $2 #-STEP
+            load $3 #end - start
+            start: load $4 #LATE
+            end: hlt
+            .equ LATE #end
+        ";
+        let mut assembler = Assembler::new();
+        let bytes = assembler.assemble(source).unwrap();
+        let cmelf = Cmelf::from_bytes(&bytes).unwrap();
+        assert_eq!(cmelf.ro_data, vec![0, 0, 0, 0, 255, 6]);
+
+        let mut vm = crate::vm::VM::new();
+        vm.load_cmelf(&bytes).unwrap();
+        vm.run();
+        assert_eq!(vm.register(0), 65);
+        assert_eq!(vm.register(1), 12);
+        assert_eq!(vm.register(2), 0xFFFD); // LOAD keeps 16 bits: -3
+        assert_eq!(vm.register(3), 4);
+        assert_eq!(vm.register(4), 20);
+    }
+
+    #[test]
+    fn test_assemble_constant_errors() {
+        let mut assembler = Assembler::new();
+        let errors = error_kinds(
+            assembler
+                .assemble(
+                    ".equ A #1\n.equ A #2\nA: hlt\nload $0 #B\nload $1 #70000\nload $2 #1 / 0",
+                )
+                .unwrap_err(),
+        );
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::SymbolAlreadyDeclared {
+                    name: "A".to_string()
+                },
+                AssemblerError::SymbolAlreadyDeclared {
+                    name: "A".to_string()
+                },
+            ]
+        );
+
+        let mut assembler = Assembler::new();
+        let errors = error_kinds(
+            assembler
+                .assemble("load $0 #B\nload $1 #70000\nload $2 #1 / 0")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::UnknownSymbol {
+                    name: "B".to_string()
+                },
+                AssemblerError::NumberOutOfRange {
+                    value: 70000,
+                    bits: 16
+                },
+                AssemblerError::DivisionByZero,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let mut assembler = Assembler::new();
+        let errors = error_kinds(assembler
+            .assemble(
+                ".asciiz \"Hi\"\n.data\nhlt\na: .byte #1\na: .byte #2\n.unknown\nload $0 @nowhere",
+            )
+            .unwrap_err());
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::DataOutsideDataSection {
+                    directive: "asciiz".to_string()
+                },
+                AssemblerError::InstructionInDataSection,
+                AssemblerError::SymbolAlreadyDeclared {
+                    name: "a".to_string()
+                },
+                AssemblerError::UnknownDirective {
+                    name: "unknown".to_string()
+                },
+                AssemblerError::InstructionInDataSection,
+            ]
+        );
+
+        let mut assembler = Assembler::new();
+        let errors = error_kinds(assembler.assemble("load $0 @nowhere").unwrap_err());
+        assert_eq!(
+            errors,
+            vec![AssemblerError::UnknownLabel {
+                name: "nowhere".to_string()
+            }]
+        );
+
+        let mut assembler = Assembler::new();
+        let errors = error_kinds(
+            assembler
+                .assemble("load $9 #1\nadd $0 $1 $6\nloadf $7 #0")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::InvalidRegister { register: 9 },
+                AssemblerError::InvalidRegister { register: 6 },
+                AssemblerError::InvalidRegister { register: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_operand_mismatch() {
+        let cases = [
+            ("eq $0 $1\nhlt", Opcode::EQ),
+            ("add $0 $1\nhlt", Opcode::ADD),
+            ("inc #5", Opcode::INC),
+            ("hlt $0", Opcode::HLT),
+            ("load $0\nhlt", Opcode::LOAD),
+        ];
+        for (source, opcode) in cases {
+            let diagnostics = Assembler::new().assemble(source).unwrap_err();
+            assert_eq!(
+                error_kinds(diagnostics),
+                vec![AssemblerError::OperandMismatch { opcode }],
+                "{}",
+                source
+            );
+        }
+
+        let diagnostics = Assembler::new().assemble("start: eq $0 $1").unwrap_err();
+        assert_eq!(diagnostics[0].location.as_ref().unwrap().column, 8);
+        assert_eq!(
+            diagnostics[0].error.help().unwrap(),
+            "usage: eq $register $register $register"
+        );
+    }
+
+    #[test]
+    fn test_register_in_number_slot() {
+        // A register where a number or label goes is a mismatch, not a missing register
+        for (source, opcode) in [
+            ("load $0 $1", Opcode::LOAD),
+            ("jgt $0 $1 $2", Opcode::JGT),
+            ("loadf $0 $1", Opcode::LOADF),
+        ] {
+            let diagnostics = Assembler::new().assemble(source).unwrap_err();
+            assert_eq!(
+                error_kinds(diagnostics.clone()),
+                vec![AssemblerError::OperandMismatch { opcode }],
+                "{}",
+                source
+            );
+            assert!(!diagnostics[0].to_string().contains("does not exist"));
+        }
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let lines = ["load $0 #1", "", "  load $0 #1 ?", "lod $1 #2"];
+        let diagnostics = Assembler::parse_program(&lines).unwrap_err();
+        assert_eq!(
+            error_kinds(diagnostics.clone()),
+            vec![
+                AssemblerError::ParseError,
+                AssemblerError::UnknownOpcode {
+                    name: "lod".to_string()
+                }
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].location,
+            Some(SourceLocation {
+                file: String::new(),
+                line: 3,
+                column: 14,
+            })
+        );
+        assert_eq!(diagnostics[1].location.as_ref().unwrap().column, 1);
+        assert_eq!(diagnostics[1].length, 3);
+
+        // Past u8, not a register
+        let diagnostics = Assembler::parse_program(&["load $256 #1"]).unwrap_err();
+        assert_eq!(error_kinds(diagnostics), vec![AssemblerError::ParseError]);
+    }
+
+    #[test]
+    fn test_assemble_error_report() {
+        let source = ".code\nstart: load $0 #1\n    load $1 @nowhere\n";
+        let diagnostics = Assembler::new().assemble(source).unwrap_err();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            [
+                "error: Unknown label `@nowhere`",
+                " --> <input>:3:13",
+                "  |",
+                "3 |     load $1 @nowhere",
+                "  |             ^^^^^^^^",
+                "  |",
+                "  = help: declare it as `nowhere:` in `.code` or `.data` section",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_assemble_error_in_macro_expansion() {
+        let source = ".macro go\nloop: load $0 @loop\nload $1 @out\n.endm\ngo\ngo";
+        let diagnostics = Assembler::new().assemble(source).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[1].location,
+            Some(SourceLocation {
+                file: String::new(),
+                line: 3,
+                column: 9,
+            })
+        );
+    }
 }
@@ -7,23 +7,92 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
     #[rustfmt::skip]
     fn from(v: CompleteStr<'a>) -> Self {
         match v {
-            CompleteStr("load") => Opcode::LOAD,
-            CompleteStr("add")  => Opcode::ADD,
-            CompleteStr("sub")  => Opcode::SUB,
-            CompleteStr("mul")  => Opcode::MUL,
-            CompleteStr("div")  => Opcode::DIV,
-            CompleteStr("hlt")  => Opcode::HLT,
-            CompleteStr("jmp")  => Opcode::JMP,
-            CompleteStr("jmpf") => Opcode::JMPF,
-            CompleteStr("jmpb") => Opcode::JMPB,
-            CompleteStr("eq")   => Opcode::EQ,
-            CompleteStr("neq")  => Opcode::NEQ,
-            CompleteStr("gtq")  => Opcode::GTQ,
-            CompleteStr("gt")   => Opcode::GT,
-            CompleteStr("ltq")  => Opcode::LTQ,
-            CompleteStr("lt")   => Opcode::LT,
-            CompleteStr("jeq")  => Opcode::JEQ,
-            _                   => Opcode::IGL,
+            CompleteStr("load")    => Opcode::LOAD,
+            CompleteStr("add")     => Opcode::ADD,
+            CompleteStr("sub")     => Opcode::SUB,
+            CompleteStr("mul")     => Opcode::MUL,
+            CompleteStr("div")     => Opcode::DIV,
+            CompleteStr("hlt")     => Opcode::HLT,
+            CompleteStr("jmp")     => Opcode::JMP,
+            CompleteStr("jmpf")    => Opcode::JMPF,
+            CompleteStr("jmpb")    => Opcode::JMPB,
+            CompleteStr("eq")      => Opcode::EQ,
+            CompleteStr("neq")     => Opcode::NEQ,
+            CompleteStr("gtq")     => Opcode::GTQ,
+            CompleteStr("gt")      => Opcode::GT,
+            CompleteStr("ltq")     => Opcode::LTQ,
+            CompleteStr("lt")      => Opcode::LT,
+            CompleteStr("jeq")     => Opcode::JEQ,
+            CompleteStr("aloc")    => Opcode::ALOC,
+            CompleteStr("jneq")    => Opcode::JNEQ,
+            CompleteStr("jeqf")    => Opcode::JEQF,
+            CompleteStr("jeqb")    => Opcode::JEQB,
+            CompleteStr("jneqf")   => Opcode::JNEQF,
+            CompleteStr("jneqb")   => Opcode::JNEQB,
+            CompleteStr("jgt")     => Opcode::JGT,
+            CompleteStr("jlt")     => Opcode::JLT,
+            CompleteStr("jgtq")    => Opcode::JGTQ,
+            CompleteStr("jltq")    => Opcode::JLTQ,
+            CompleteStr("and")     => Opcode::AND,
+            CompleteStr("or")      => Opcode::OR,
+            CompleteStr("xor")     => Opcode::XOR,
+            CompleteStr("not")     => Opcode::NOT,
+            CompleteStr("shl")     => Opcode::SHL,
+            CompleteStr("shr")     => Opcode::SHR,
+            CompleteStr("sar")     => Opcode::SAR,
+            CompleteStr("andi")    => Opcode::ANDI,
+            CompleteStr("ori")     => Opcode::ORI,
+            CompleteStr("xori")    => Opcode::XORI,
+            CompleteStr("shli")    => Opcode::SHLI,
+            CompleteStr("shri")    => Opcode::SHRI,
+            CompleteStr("sari")    => Opcode::SARI,
+            CompleteStr("inc")     => Opcode::INC,
+            CompleteStr("dec")     => Opcode::DEC,
+            CompleteStr("mov")     => Opcode::MOV,
+            CompleteStr("addi")    => Opcode::ADDI,
+            CompleteStr("mod")     => Opcode::MOD,
+            CompleteStr("mvr")     => Opcode::MVR,
+            CompleteStr("addt")    => Opcode::ADDT,
+            CompleteStr("subt")    => Opcode::SUBT,
+            CompleteStr("mult")    => Opcode::MULT,
+            CompleteStr("jz")      => Opcode::JZ,
+            CompleteStr("jnz")     => Opcode::JNZ,
+            CompleteStr("jn")      => Opcode::JN,
+            CompleteStr("jc")      => Opcode::JC,
+            CompleteStr("jo")      => Opcode::JO,
+            CompleteStr("loadf")   => Opcode::LOADF,
+            CompleteStr("addf")    => Opcode::ADDF,
+            CompleteStr("subf")    => Opcode::SUBF,
+            CompleteStr("mulf")    => Opcode::MULF,
+            CompleteStr("divf")    => Opcode::DIVF,
+            CompleteStr("eqf")     => Opcode::EQF,
+            CompleteStr("neqf")    => Opcode::NEQF,
+            CompleteStr("gtf")     => Opcode::GTF,
+            CompleteStr("ltf")     => Opcode::LTF,
+            CompleteStr("gtqf")    => Opcode::GTQF,
+            CompleteStr("ltqf")    => Opcode::LTQF,
+            CompleteStr("itof")    => Opcode::ITOF,
+            CompleteStr("ftoi")    => Opcode::FTOI,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("ldb")     => Opcode::LDB,
+            CompleteStr("stb")     => Opcode::STB,
+            CompleteStr("native")  => Opcode::NATIVE,
+            CompleteStr("spawn")   => Opcode::SPAWN,
+            CompleteStr("yield")   => Opcode::YIELD,
+            CompleteStr("join")    => Opcode::JOIN,
+            CompleteStr("send")    => Opcode::SEND,
+            CompleteStr("recv")    => Opcode::RECV,
+            CompleteStr("ldw")     => Opcode::LDW,
+            CompleteStr("stw")     => Opcode::STW,
+            CompleteStr("cas")     => Opcode::CAS,
+            CompleteStr("fadd")    => Opcode::FADD,
+            CompleteStr("fence")   => Opcode::FENCE,
+            CompleteStr("new")     => Opcode::NEW,
+            CompleteStr("free")    => Opcode::FREE,
+            CompleteStr("gc")      => Opcode::GC,
+            CompleteStr("push")    => Opcode::PUSH,
+            CompleteStr("pop")     => Opcode::POP,
+            _                      => Opcode::IGL,
         }
     }
 }
@@ -36,6 +105,8 @@ named!(pub opcode<CompleteStr, Token>,
     )
 );
 
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -47,6 +118,9 @@ mod tests {
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
 
+        let (_, token) = opcode(CompleteStr("aloc")).unwrap();
+        assert_eq!(token, Token::Op { code: Opcode::ALOC });
+
         let result = opcode(CompleteStr("aold"));
         assert_eq!(result.is_ok(), true);
         let (rest, token) = result.unwrap();
@@ -1,17 +1,50 @@
-use nom::{digit, named, tag, types::CompleteStr, ws};
+use nom::{alt, digit, map_res, named, one_of, opt, recognize, tag, tuple, types::CompleteStr, ws};
 
+use super::expression::Expression;
+use super::expression_parser::expression;
+use super::label_parser::label_usage;
+use super::register_parser::register;
+use super::string_parser::string_operand;
 use super::token::Token;
 
+// `#100`, `#-OFFSET`, `#(BUF_SIZE * 4 + 1)`, `#label + 8`
 named!(pub integer_operand<CompleteStr, Token>, ws!(
     do_parse!(
-        tag!("#") >> reg_num: digit >> (Token::Number {
-            value: reg_num.parse::<i32>().unwrap()
+        tag!("#") >> expr: expression >> (match expr {
+            Expression::Number(value) => Token::Number { value },
+            expr => Token::Expression { expr },
         })
     )
 ));
 
+// `#1.5`, `#-0.25`, `#6.02e23`. The dot tells it from an integer
+named!(pub float_operand<CompleteStr, Token>, ws!(
+    do_parse!(
+        tag!("#") >>
+        value: map_res!(
+            recognize!(tuple!(
+                opt!(tag!("-")),
+                digit,
+                tag!("."),
+                digit,
+                opt!(tuple!(one_of!("eE"), opt!(one_of!("+-")), digit))
+            )),
+            |text: CompleteStr| text.parse::<f64>()
+        ) >>
+        (Token::Float { bits: value.to_bits() })
+    )
+));
+
+// <register | float_operand | integer_operand | label_usage | string_operand>
+named!(pub operand<CompleteStr, Token>,
+    alt!(register | float_operand | integer_operand | label_usage | string_operand)
+);
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::assembler::expression::BinaryOperator;
 
     #[test]
     fn test_parse_integer_operand() {
@@ -26,4 +59,64 @@ mod tests {
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_integer_operand_expression() {
+        let result = integer_operand(CompleteStr("#label + 8"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::Expression {
+                    expr: Expression::Binary(
+                        Box::new(Expression::Symbol("label".to_string())),
+                        BinaryOperator::Add,
+                        Box::new(Expression::Number(8)),
+                    )
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        let result = operand(CompleteStr("#-1.5e2"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::Float {
+                    bits: (-150.0f64).to_bits()
+                }
+            ))
+        );
+        let result = operand(CompleteStr("#15"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Number { value: 15 })));
+        assert!(float_operand(CompleteStr("#1.")).is_err());
+    }
+
+    #[test]
+    fn test_parse_operand() {
+        let result = operand(CompleteStr("@msg"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::LabelUsage {
+                    name: "msg".to_string()
+                }
+            ))
+        );
+
+        let result = operand(CompleteStr("\"text\""));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::String {
+                    value: "text".to_string()
+                }
+            ))
+        );
+    }
 }
@@ -0,0 +1,430 @@
+use std::collections::HashSet;
+
+use super::expression::Expression;
+use super::instruction_parser::AssemblerInstruction;
+use super::token::Token;
+use crate::vm::instruction::{Opcode, OperandKind};
+
+/*
+Peephole optimizer, enabled by `Assembler::set_optimize`. Runs on parsed instructions before
+labels are resolved, so every label is placed again on the shrunk code. Until nothing changes:
+
+- jumps to the next instruction are removed
+- `load $t #x` + `add $a $t $c` becomes `addi $a #x $c` when $t is overwritten before it is read
+- instructions after `hlt` or an unconditional jump are dropped up to the next label
+- `load $r @label` + `jmp $r` becomes `jgtq $0 $0 @label` when $r is overwritten at the label
+  before it is read
+
+Registers and flags seen at HLT, by syscalls, natives and faults are kept. Jump targets must be
+labels (`@label`, `#label`, expressions of labels); hand computed offsets are not adjusted
+*/
+pub fn optimize(mut instructions: Vec<AssemblerInstruction>) -> Vec<AssemblerInstruction> {
+    let labels: HashSet<String> = instructions
+        .iter()
+        .filter_map(|instruction| instruction.label_name().map(str::to_string))
+        .collect();
+    loop {
+        let before = instructions.len();
+        remove_jumps_to_next(&mut instructions, &labels);
+        fold_constant_add(&mut instructions);
+        shorten_branches(&mut instructions, &labels);
+        remove_unreachable(&mut instructions);
+        if instructions.len() == before {
+            return instructions;
+        }
+    }
+}
+
+fn remove_jumps_to_next(instructions: &mut Vec<AssemblerInstruction>, labels: &HashSet<String>) {
+    let mut index = 0;
+    while index < instructions.len() {
+        let removable = jump_target(instructions, index, labels)
+            .is_some_and(|target| next_labels(instructions, index).contains(&target));
+        if removable {
+            // A label on the jump now marks the next instruction
+            match label_only(&instructions[index]) {
+                Some(label) => instructions[index] = label,
+                None => {
+                    instructions.remove(index);
+                    continue;
+                }
+            }
+        }
+        index += 1;
+    }
+}
+
+fn fold_constant_add(instructions: &mut Vec<AssemblerInstruction>) {
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        if let Some(addi) = constant_add(&instructions[index], &instructions[index + 1]) {
+            let written = addi.operand3.clone();
+            let temporary = register(&instructions[index].operand1);
+            if written == instructions[index].operand1
+                || overwritten_before_read(instructions, index + 2, temporary)
+            {
+                instructions[index] = AssemblerInstruction {
+                    label: instructions[index].label.clone(),
+                    ..addi
+                };
+                instructions.remove(index + 1);
+            }
+        }
+        index += 1;
+    }
+}
+
+// `load $t #x` + `add` reading $t once -> `addi`. Immediate of ADDI is sign-extended
+fn constant_add(
+    load: &AssemblerInstruction,
+    add: &AssemblerInstruction,
+) -> Option<AssemblerInstruction> {
+    if opcode(load) != Some(Opcode::LOAD) || opcode(add) != Some(Opcode::ADD) || add.label.is_some()
+    {
+        return None;
+    }
+    let value = match load.operand2 {
+        Some(Token::Number { value }) if (0..=i16::MAX as i32).contains(&value) => value,
+        _ => return None,
+    };
+    let other = match (&add.operand1, &add.operand2) {
+        (a, b) if *a == load.operand1 && *b != load.operand1 => b.clone(),
+        (a, b) if *b == load.operand1 && *a != load.operand1 => a.clone(),
+        _ => return None,
+    };
+    Some(AssemblerInstruction {
+        opcode: Some(Token::Op { code: Opcode::ADDI }),
+        operand1: other,
+        operand2: Some(Token::Number { value }),
+        ..add.clone()
+    })
+}
+
+fn shorten_branches(instructions: &mut Vec<AssemblerInstruction>, labels: &HashSet<String>) {
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        let load = &instructions[index];
+        let jump = &instructions[index + 1];
+        let target = match label_operand(&load.operand2, labels) {
+            Some(target)
+                if opcode(load) == Some(Opcode::LOAD)
+                    && opcode(jump) == Some(Opcode::JMP)
+                    && jump.label.is_none()
+                    && jump.operand1 == load.operand1 =>
+            {
+                target
+            }
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        let at_target = instructions
+            .iter()
+            .position(|instruction| instruction.label_name() == Some(target.as_str()));
+        let scratch = register(&load.operand1);
+        if at_target.is_some_and(|start| overwritten_before_read(instructions, start, scratch)) {
+            let zero = Some(Token::Register { reg_num: 0 });
+            instructions[index] = AssemblerInstruction {
+                opcode: Some(Token::Op { code: Opcode::JGTQ }),
+                operand1: zero.clone(),
+                operand2: zero,
+                operand3: Some(Token::LabelUsage { name: target }),
+                ..instructions[index].clone()
+            };
+            instructions.remove(index + 1);
+        }
+        index += 1;
+    }
+}
+
+fn remove_unreachable(instructions: &mut Vec<AssemblerInstruction>) {
+    let mut index = 0;
+    while index < instructions.len() {
+        if is_unconditional(&instructions[index]) {
+            while instructions.get(index + 1).is_some_and(|next| {
+                next.is_opcode() && next.label.is_none() && !next.is_directive()
+            }) {
+                instructions.remove(index + 1);
+            }
+        }
+        index += 1;
+    }
+}
+
+fn is_unconditional(instruction: &AssemblerInstruction) -> bool {
+    match opcode(instruction) {
+        Some(Opcode::HLT | Opcode::JMP) => true,
+        Some(Opcode::JGTQ | Opcode::JLTQ) => instruction.operand1 == instruction.operand2,
+        _ => false,
+    }
+}
+
+// Label a jump goes to, when known without running the program
+fn jump_target(
+    instructions: &[AssemblerInstruction],
+    index: usize,
+    labels: &HashSet<String>,
+) -> Option<String> {
+    let jump = &instructions[index];
+    match opcode(jump)? {
+        Opcode::JGT | Opcode::JLT | Opcode::JGTQ | Opcode::JLTQ => {
+            label_operand(&jump.operand3, labels)
+        }
+        Opcode::JMP
+        | Opcode::JEQ
+        | Opcode::JNEQ
+        | Opcode::JZ
+        | Opcode::JNZ
+        | Opcode::JN
+        | Opcode::JC
+        | Opcode::JO
+            if index > 0 && jump.label.is_none() =>
+        {
+            let load = &instructions[index - 1];
+            if opcode(load) == Some(Opcode::LOAD) && load.operand1 == jump.operand1 {
+                label_operand(&load.operand2, labels)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// Labels placed on the instruction after `index`
+fn next_labels(instructions: &[AssemblerInstruction], index: usize) -> Vec<String> {
+    let mut labels = vec![];
+    for instruction in &instructions[index + 1..] {
+        if instruction.is_directive() {
+            break;
+        }
+        labels.extend(instruction.label_name().map(str::to_string));
+        if instruction.is_opcode() {
+            break;
+        }
+    }
+    labels
+}
+
+// Following execution from `start`, `register` is written before anything can read it
+fn overwritten_before_read(
+    instructions: &[AssemblerInstruction],
+    start: usize,
+    register: Option<u8>,
+) -> bool {
+    let Some(register) = register else {
+        return false;
+    };
+    for instruction in instructions.iter().skip(start) {
+        if instruction.is_directive() {
+            return false;
+        }
+        if !instruction.is_opcode() {
+            continue;
+        }
+        let Some((reads, write)) = register_effects(instruction) else {
+            return false;
+        };
+        if reads.contains(&register) {
+            return false;
+        }
+        if write == Some(register) {
+            return true;
+        }
+    }
+    false
+}
+
+// Integer registers read and written. None when effects are not local: control flow, host
+// calls and instructions which may fault
+fn register_effects(instruction: &AssemblerInstruction) -> Option<(Vec<u8>, Option<u8>)> {
+    let code = opcode(instruction)?;
+    let mut registers: Vec<u8> = code
+        .operands()
+        .iter()
+        .zip(instruction.operands())
+        .filter_map(|(kind, token)| match (kind, token) {
+            (OperandKind::Register, Token::Register { reg_num }) => Some(*reg_num),
+            _ => None,
+        })
+        .collect();
+    let write = match code {
+        Opcode::LOAD | Opcode::MVR => return Some((vec![], registers.first().copied())),
+        Opcode::INC | Opcode::DEC => registers.first().copied(),
+        Opcode::FTOI => registers.pop(),
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::AND
+        | Opcode::OR
+        | Opcode::XOR
+        | Opcode::NOT
+        | Opcode::SHL
+        | Opcode::SHR
+        | Opcode::SAR
+        | Opcode::ANDI
+        | Opcode::ORI
+        | Opcode::XORI
+        | Opcode::SHLI
+        | Opcode::SHRI
+        | Opcode::SARI
+        | Opcode::MOV
+        | Opcode::ADDI => registers.pop(),
+        Opcode::EQ
+        | Opcode::NEQ
+        | Opcode::GT
+        | Opcode::LT
+        | Opcode::GTQ
+        | Opcode::LTQ
+        | Opcode::ADDF
+        | Opcode::SUBF
+        | Opcode::MULF
+        | Opcode::DIVF
+        | Opcode::EQF
+        | Opcode::NEQF
+        | Opcode::GTF
+        | Opcode::LTF
+        | Opcode::GTQF
+        | Opcode::LTQF
+        | Opcode::ITOF
+        | Opcode::ALOC => None,
+        _ => return None,
+    };
+    Some((registers, write))
+}
+
+fn opcode(instruction: &AssemblerInstruction) -> Option<Opcode> {
+    match instruction.opcode {
+        Some(Token::Op { code }) => Some(code),
+        _ => None,
+    }
+}
+
+fn register(token: &Option<Token>) -> Option<u8> {
+    match token {
+        Some(Token::Register { reg_num }) => Some(*reg_num),
+        _ => None,
+    }
+}
+
+// `@name` or `#name` naming a label, not a constant
+fn label_operand(token: &Option<Token>, labels: &HashSet<String>) -> Option<String> {
+    let name = match token {
+        Some(Token::LabelUsage { name }) => name,
+        Some(Token::Expression {
+            expr: Expression::Symbol(name),
+        }) => name,
+        _ => return None,
+    };
+    labels.contains(name).then(|| name.clone())
+}
+
+fn label_only(instruction: &AssemblerInstruction) -> Option<AssemblerInstruction> {
+    instruction.label.as_ref()?;
+    Some(AssemblerInstruction {
+        opcode: None,
+        operand1: None,
+        operand2: None,
+        operand3: None,
+        ..instruction.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn assemble(source: &str, optimize: bool) -> Vec<u8> {
+        let mut assembler = Assembler::new();
+        assembler.set_optimize(optimize);
+        assembler.assemble(source).unwrap()
+    }
+
+    fn assert_optimizes_to(source: &str, expected: &str) {
+        assert_eq!(assemble(source, true), assemble(expected, false));
+    }
+
+    #[test]
+    fn test_remove_jumps_to_next() {
+        assert_optimizes_to(
+            "load $0 #1\nload $4 @next\njmp $4\nnext: jgt $0 $1 @end\nend: hlt",
+            "load $0 #1\nload $4 @next\nnext:\nend: hlt",
+        );
+        // Another path may reach the jump with a different $4
+        let labeled = "load $4 @next\nagain: jeq $4\nnext: hlt";
+        assert_optimizes_to(labeled, labeled);
+    }
+
+    #[test]
+    fn test_fold_constant_add() {
+        assert_optimizes_to(
+            "load $5 #3\nadd $0 $5 $0\nload $5 #4\nadd $5 $1 $1\nload $5 #0\nhlt",
+            "addi $0 #3 $0\naddi $1 #4 $1\nload $5 #0\nhlt",
+        );
+        // $5 is still 3 at HLT, #40000 does not fit ADDI
+        for kept in [
+            "load $5 #3\nadd $0 $5 $0\nhlt",
+            "load $5 #40000\nadd $0 $5 $0\nload $5 #0\nhlt",
+        ] {
+            assert_optimizes_to(kept, kept);
+        }
+    }
+
+    #[test]
+    fn test_remove_unreachable() {
+        assert_optimizes_to(
+            "jgtq $1 $1 @end\ninc $0\ninc $0\nend: hlt\ninc $0\n.data\nmsg: .asciiz \"Hi\"",
+            // The jump now goes to the next instruction
+            "end: hlt\n.data\nmsg: .asciiz \"Hi\"",
+        );
+    }
+
+    #[test]
+    fn test_shorten_branches() {
+        assert_optimizes_to(
+            "start: load $4 #0\ninc $0\nload $1 #3\njlt $0 $1 @body\nhlt\nbody: load $4 @start\njmp $4",
+            "start: load $4 #0\ninc $0\nload $1 #3\njlt $0 $1 @body\nhlt\nbody: jgtq $0 $0 @start",
+        );
+        // $4 is read at the target
+        let kept = "start: mov $4 $0\njlt $0 $1 @start\nload $4 @start\njmp $4";
+        assert_optimizes_to(kept, kept);
+    }
+
+    #[test]
+    fn test_optimized_program_behaves_the_same() {
+        // Toy compiler output: $0 = sum of 1..=10, $1 = 10
+        let source = "
+            load $0 #0
+            load $1 #0
+            load $5 #10
+            mov $5 $2
+            loop: load $5 #1
+            add $1 $5 $1
+            add $0 $1 $0
+            load $4 @check
+            jmp $4
+            check: lt $1 $2 $5
+            load $4 @loop
+            jeq $4
+            load $4 @done
+            jmp $4
+            inc $0
+            done: load $4 #0
+            load $5 #0
+            hlt
+        ";
+        let run = |image: &[u8]| {
+            let mut vm = VM::new();
+            vm.load_cmelf(image).unwrap();
+            vm.run();
+            (*vm.registers(), vm.flags())
+        };
+        let plain = assemble(source, false);
+        let optimized = assemble(source, true);
+        assert!(optimized.len() < plain.len());
+        assert_eq!(run(&optimized), run(&plain));
+        assert_eq!(run(&optimized).0, [55, 10, 10, 0, 0, 0]);
+    }
+}
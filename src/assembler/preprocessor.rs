@@ -0,0 +1,490 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nom::types::CompleteStr;
+
+use super::assembler_errors::AssemblerError;
+use super::diagnostic::{AssemblerDiagnostic, SourceLocation};
+use super::string_parser::string_operand;
+use super::token::Token;
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// Line of the expanded source with the place it came from
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    // Points at the first non blank character
+    pub fn location(&self) -> SourceLocation {
+        let indent = self.text.chars().take_while(|c| c.is_whitespace()).count();
+        SourceLocation {
+            file: self.file.clone(),
+            line: self.line,
+            column: indent + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    definition: SourceLine, // `.macro` line
+    name: String,
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+/*
+.macro name arg1 arg2   Start macro definition. `\arg1` in the body is replaced by the argument
+.endm                   End macro definition
+.include "file.asm"     Insert file contents. Path is relative to the including file
+
+Labels declared inside a macro body are local: every expansion gets unique names.
+*/
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    macros: Vec<Macro>,
+    expansions: usize, // Counter for unique local labels
+    include_stack: Vec<PathBuf>,
+    errors: Vec<AssemblerDiagnostic>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor::default()
+    }
+
+    pub fn process(
+        &mut self,
+        raw: &str,
+        file: &str,
+    ) -> Result<Vec<SourceLine>, Vec<AssemblerDiagnostic>> {
+        let lines = Self::source_lines(raw, file);
+        let mut output = vec![];
+        self.process_lines(&lines, Path::new(file).parent(), 0, &mut output);
+
+        if self.errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    pub fn process_file(
+        &mut self,
+        path: &Path,
+    ) -> Result<Vec<SourceLine>, Vec<AssemblerDiagnostic>> {
+        match fs::read_to_string(path) {
+            Ok(raw) => {
+                self.include_stack.push(path.to_path_buf());
+                let result = self.process(&raw, &path.to_string_lossy());
+                self.include_stack.pop();
+                result
+            }
+            Err(_) => Err(vec![AssemblerDiagnostic::new(
+                AssemblerError::IncludeNotFound {
+                    path: path.to_string_lossy().to_string(),
+                },
+            )]),
+        }
+    }
+
+    fn error(&mut self, error: AssemblerError, source: &SourceLine) {
+        let needle = error.token();
+        self.errors.push(AssemblerDiagnostic::at_token(
+            error,
+            source.location(),
+            &source.text,
+            needle.as_deref(),
+        ));
+    }
+
+    fn source_lines(raw: &str, file: &str) -> Vec<SourceLine> {
+        raw.lines()
+            .enumerate()
+            .map(|(index, text)| SourceLine {
+                file: file.to_string(),
+                line: index + 1,
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    fn process_lines(
+        &mut self,
+        lines: &[SourceLine],
+        dir: Option<&Path>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) {
+        let mut definition: Option<Macro> = None;
+        for source in lines {
+            let words: Vec<&str> = source.text.split_whitespace().collect();
+            match (words.first().copied(), definition.as_mut()) {
+                (Some(".endm"), Some(_)) => {
+                    let finished = definition.take().unwrap();
+                    self.macros.retain(|m| m.name != finished.name);
+                    self.macros.push(finished);
+                }
+                (Some(".macro"), Some(_)) => {
+                    self.error(AssemblerError::NestedMacroDefinition, source);
+                }
+                (_, Some(current)) => current.body.push(source.clone()),
+                (Some(".macro"), None) => match words.get(1) {
+                    Some(name) => {
+                        definition = Some(Macro {
+                            definition: source.clone(),
+                            name: name.to_string(),
+                            params: words[2..].iter().map(|p| p.to_string()).collect(),
+                            body: vec![],
+                        })
+                    }
+                    None => self.error(
+                        AssemblerError::MissingOperand {
+                            directive: "macro".to_string(),
+                        },
+                        source,
+                    ),
+                },
+                (Some(".endm"), None) => {
+                    self.error(AssemblerError::UnexpectedEndm, source);
+                }
+                (Some(".include"), None) => self.include(source, dir, depth, output),
+                _ => self.expand_line(source, dir, depth, output),
+            }
+        }
+
+        if let Some(unfinished) = definition {
+            self.error(
+                AssemblerError::MacroWithoutEnd {
+                    name: unfinished.name,
+                },
+                &unfinished.definition,
+            );
+        }
+    }
+
+    fn include(
+        &mut self,
+        source: &SourceLine,
+        dir: Option<&Path>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) {
+        let argument = source.text.trim_start()[".include".len()..].trim();
+        let file = match string_operand(CompleteStr(argument)) {
+            Ok((rest, Token::String { value })) if rest.is_empty() => value,
+            _ => {
+                self.error(
+                    AssemblerError::InvalidOperand {
+                        directive: "include".to_string(),
+                    },
+                    source,
+                );
+                return;
+            }
+        };
+
+        let path = dir.map_or_else(|| PathBuf::from(&file), |dir| dir.join(&file));
+        if self.include_stack.contains(&path) || depth >= MAX_EXPANSION_DEPTH {
+            self.error(
+                AssemblerError::RecursiveInclude {
+                    path: path.to_string_lossy().to_string(),
+                },
+                source,
+            );
+            return;
+        }
+        let raw = match fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(_) => {
+                self.error(
+                    AssemblerError::IncludeNotFound {
+                        path: path.to_string_lossy().to_string(),
+                    },
+                    source,
+                );
+                return;
+            }
+        };
+
+        let lines = Self::source_lines(&raw, &path.to_string_lossy());
+        self.include_stack.push(path.clone());
+        self.process_lines(&lines, path.parent(), depth + 1, output);
+        self.include_stack.pop();
+    }
+
+    // `[label:] name arg1 arg2` expands when `name` is a defined macro
+    fn expand_line(
+        &mut self,
+        source: &SourceLine,
+        dir: Option<&Path>,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) {
+        let mut words: Vec<&str> = source.text.split_whitespace().collect();
+        let label = match words.first() {
+            Some(word) if word.ends_with(':') => Some(words.remove(0)),
+            _ => None,
+        };
+        let found = words
+            .first()
+            .and_then(|name| self.macros.iter().find(|m| m.name == *name))
+            .cloned();
+        let Some(found) = found else {
+            output.push(source.clone());
+            return;
+        };
+
+        let args = &words[1..];
+        if args.len() != found.params.len() {
+            self.error(
+                AssemblerError::MacroArgumentCount {
+                    name: found.name,
+                    expected: found.params.len(),
+                    found: args.len(),
+                },
+                source,
+            );
+            return;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.error(AssemblerError::RecursiveMacro { name: found.name }, source);
+            return;
+        }
+
+        if let Some(label) = label {
+            output.push(SourceLine {
+                text: label.to_string(),
+                ..source.clone()
+            });
+        }
+
+        self.expansions += 1;
+        let body = Self::substitute(&found, args, self.expansions);
+        self.process_lines(&body, dir, depth + 1, output);
+    }
+
+    fn substitute(found: &Macro, args: &[&str], expansion: usize) -> Vec<SourceLine> {
+        // Longest names first: `\ab` must not be replaced as `\a` + `b`
+        let mut params: Vec<(&String, &str)> =
+            found.params.iter().zip(args.iter().copied()).collect();
+        params.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+        let locals: Vec<&str> = found
+            .body
+            .iter()
+            .filter_map(|source| {
+                let first = source.text.split_whitespace().next()?;
+                first.strip_suffix(':')
+            })
+            .collect();
+
+        found
+            .body
+            .iter()
+            .map(|source| {
+                let mut text = source.text.clone();
+                for (name, value) in &params {
+                    text = text.replace(&format!("\\{}", name), value);
+                }
+                for local in &locals {
+                    text = Self::rename_label(&text, local, &format!("{}__{}", local, expansion));
+                }
+                SourceLine {
+                    text,
+                    ..source.clone()
+                }
+            })
+            .collect()
+    }
+
+    // Renames `name:` declaration and `@name` usages, leaving longer identifiers alone
+    fn rename_label(text: &str, name: &str, renamed: &str) -> String {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(position) = rest.find(name) {
+            let (before, after) = (&rest[..position], &rest[position + name.len()..]);
+            let prev = before.chars().last();
+            let next = after.chars().next();
+            let declaration = before.trim().is_empty() && next == Some(':');
+            let usage = prev == Some('@');
+            let whole = !prev.is_some_and(is_ident) && !next.is_some_and(is_ident);
+
+            result.push_str(before);
+            if whole && (declaration || usage) {
+                result.push_str(renamed);
+            } else {
+                result.push_str(name);
+            }
+            rest = after;
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(diagnostics: &[AssemblerDiagnostic]) -> Vec<AssemblerError> {
+        diagnostics.iter().map(|d| d.error.clone()).collect()
+    }
+
+    fn texts(lines: Vec<SourceLine>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| line.text.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        let source = "
+            .macro jump_if_eq a b target
+            eq \\a \\b $5
+            load $5 \\target
+            jeq $5
+            .endm
+            start: jump_if_eq $0 $1 @start
+        ";
+        let lines = Preprocessor::new().process(source, "test.asm").unwrap();
+        assert_eq!(
+            texts(lines),
+            vec!["", "start:", "eq $0 $1 $5", "load $5 @start", "jeq $5", ""]
+        );
+    }
+
+    #[test]
+    fn test_macro_local_labels() {
+        let source = "
+            .macro spin
+            loop: load $0 @loop
+            jmp $0
+            .endm
+            spin
+            spin
+        ";
+        let lines = Preprocessor::new().process(source, "test.asm").unwrap();
+        assert_eq!(
+            texts(lines)
+                .into_iter()
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>(),
+            vec![
+                "loop__1: load $0 @loop__1",
+                "jmp $0",
+                "loop__2: load $0 @loop__2",
+                "jmp $0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macro_source_lines() {
+        let source = ".macro one\nhlt\n.endm\none";
+        let lines = Preprocessor::new().process(source, "test.asm").unwrap();
+        assert_eq!(
+            lines,
+            vec![SourceLine {
+                file: "test.asm".to_string(),
+                line: 2,
+                text: "hlt".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rename_label() {
+        assert_eq!(
+            Preprocessor::rename_label("loop: load $0 @loop_end", "loop", "loop__1"),
+            "loop__1: load $0 @loop_end"
+        );
+        assert_eq!(
+            Preprocessor::rename_label("load $0 @loop", "loop", "loop__1"),
+            "load $0 @loop__1"
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let diagnostics = Preprocessor::new()
+            .process(".macro a x\nhlt\n.endm\n  a\n.endm\n.macro b", "test.asm")
+            .unwrap_err();
+        assert_eq!(
+            errors(&diagnostics),
+            vec![
+                AssemblerError::MacroArgumentCount {
+                    name: "a".to_string(),
+                    expected: 1,
+                    found: 0
+                },
+                AssemblerError::UnexpectedEndm,
+                AssemblerError::MacroWithoutEnd {
+                    name: "b".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].location,
+            Some(SourceLocation {
+                file: "test.asm".to_string(),
+                line: 4,
+                column: 3,
+            })
+        );
+        assert_eq!(diagnostics[2].location.as_ref().unwrap().line, 6);
+
+        let diagnostics = Preprocessor::new()
+            .process(".macro a\na\n.endm\na", "test.asm")
+            .unwrap_err();
+        assert_eq!(
+            errors(&diagnostics),
+            vec![AssemblerError::RecursiveMacro {
+                name: "a".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("cm_vm_include_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.asm"), ".macro stop\nhlt\n.endm\n").unwrap();
+        fs::write(dir.join("main.asm"), ".include \"lib.asm\"\nstop\n").unwrap();
+        fs::write(dir.join("self.asm"), ".include \"self.asm\"\n").unwrap();
+
+        let lines = Preprocessor::new()
+            .process_file(&dir.join("main.asm"))
+            .unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, "hlt");
+        assert!(lines[0].file.ends_with("lib.asm"));
+
+        let diagnostics = Preprocessor::new()
+            .process_file(&dir.join("self.asm"))
+            .unwrap_err();
+        assert!(matches!(
+            diagnostics[0].error,
+            AssemblerError::RecursiveInclude { .. }
+        ));
+
+        let diagnostics = Preprocessor::new()
+            .process(".include \"missing.asm\"", "main.asm")
+            .unwrap_err();
+        assert_eq!(
+            errors(&diagnostics),
+            vec![AssemblerError::IncludeNotFound {
+                path: "missing.asm".to_string()
+            }]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
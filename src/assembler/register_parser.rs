@@ -1,15 +1,17 @@
-use nom::{digit, named, tag, types::CompleteStr, ws};
+use nom::{digit, map_res, named, tag, types::CompleteStr, ws};
 
 use super::token::Token;
 
 named!(pub register<CompleteStr, Token>, ws!(
     do_parse!(
-        tag!("$") >> reg_num: digit >> (Token::Register {
-            reg_num: reg_num.parse::<u8>().unwrap()
+        tag!("$") >> reg_num: map_res!(digit, |digits: CompleteStr| digits.parse::<u8>()) >> (Token::Register {
+            reg_num
         })
     )
 ));
 
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -24,4 +26,13 @@ mod tests {
         let result = register(CompleteStr("$a"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_register_out_of_u8() {
+        assert_eq!(
+            register(CompleteStr("$255")),
+            Ok((CompleteStr(""), Token::Register { reg_num: 255 }))
+        );
+        assert!(register(CompleteStr("$256")).is_err());
+    }
 }
@@ -0,0 +1,107 @@
+use nom::{do_parse, escaped, expr_res, named, none_of, one_of, opt, tag, types::CompleteStr, ws};
+
+use super::token::Token;
+
+/*
+Supported escape sequences:
+\n \r \t \0 \\ \"  - Usual meaning
+\xNN               - ASCII character with hex code NN (00..7F)
+*/
+fn unescape(raw: &str) -> Result<String, ()> {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some('"') => result.push('"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(code) if hex.len() == 2 && code.is_ascii() => result.push(code as char),
+                    _ => return Err(()),
+                }
+            }
+            _ => return Err(()),
+        }
+    }
+    Ok(result)
+}
+
+named!(string_body<CompleteStr, CompleteStr>,
+    escaped!(none_of!("\\\""), '\\', one_of!("nrt0\\\"x"))
+);
+
+named!(string_literal<CompleteStr, Token>,
+    do_parse!(
+        tag!("\"") >>
+        body: opt!(string_body) >>
+        tag!("\"") >>
+        value: expr_res!(unescape(body.map_or("", |body| body.0))) >>
+        (Token::String { value })
+    )
+);
+
+// `"Hello"`
+named!(pub string_operand<CompleteStr, Token>, ws!(string_literal));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_string_operand() {
+        let result = string_operand(CompleteStr("\"Hello, World\""));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::String {
+                value: "Hello, World".to_string()
+            }
+        );
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = string_operand(CompleteStr("\"Unterminated"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_string_operand_escapes() {
+        let result = string_operand(CompleteStr(r#""  a\tb\n\"q\" \\ \x41\x7e""#));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::String {
+                    value: "  a\tb\n\"q\" \\ A~".to_string()
+                }
+            ))
+        );
+
+        let result = string_operand(CompleteStr("\"\""));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::String {
+                    value: "".to_string()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_string_operand_bad_escapes() {
+        assert!(string_operand(CompleteStr(r#""\q""#)).is_err());
+        assert!(string_operand(CompleteStr(r#""\x4""#)).is_err());
+        assert!(string_operand(CompleteStr(r#""\xFF""#)).is_err());
+    }
+}
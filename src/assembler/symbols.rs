@@ -0,0 +1,122 @@
+use super::expression::Expression;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SymbolType {
+    Code, // Byte offset into the code section
+    Data, // Byte offset into the read-only data section
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u32,
+    pub symbol_type: SymbolType,
+}
+
+impl Symbol {
+    pub fn new(name: String, symbol_type: SymbolType, offset: u32) -> Symbol {
+        Symbol {
+            name,
+            offset,
+            symbol_type,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    strings: Vec<(String, u32)>, // String operands interned into read-only data
+    floats: Vec<(u64, u32)>,     // Float operands (f64 bits) interned into read-only data
+    constants: Vec<(String, Expression)>, // `.equ` / `.set` definitions
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: vec![],
+            strings: vec![],
+            floats: vec![],
+            constants: vec![],
+        }
+    }
+
+    // `.set` redefines, `.equ` is checked by the assembler before
+    pub fn set_constant(&mut self, name: String, value: Expression) {
+        match self
+            .constants
+            .iter_mut()
+            .find(|(constant, _)| *constant == name)
+        {
+            Some(existing) => existing.1 = value,
+            None => self.constants.push((name, value)),
+        }
+    }
+
+    pub fn constant(&self, name: &str) -> Option<Expression> {
+        self.constants
+            .iter()
+            .find(|(constant, _)| constant == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn add_string(&mut self, value: String, offset: u32) {
+        self.strings.push((value, offset));
+    }
+
+    pub fn string_offset(&self, value: &str) -> Option<u32> {
+        self.strings
+            .iter()
+            .find(|(string, _)| string == value)
+            .map(|(_, offset)| *offset)
+    }
+
+    pub fn add_float(&mut self, bits: u64, offset: u32) {
+        self.floats.push((bits, offset));
+    }
+
+    pub fn float_offset(&self, bits: u64) -> Option<u32> {
+        self.floats
+            .iter()
+            .find(|(float, _)| *float == bits)
+            .map(|(_, offset)| *offset)
+    }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    pub fn has_symbol(&self, name: &str) -> bool {
+        self.symbols.iter().any(|symbol| symbol.name == name)
+    }
+
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut table = SymbolTable::new();
+        table.add_symbol(Symbol::new("test".to_string(), SymbolType::Code, 12));
+        assert!(table.has_symbol("test"));
+        assert_eq!(table.symbol_value("test"), Some(12));
+        assert_eq!(table.symbol_value("missing"), None);
+
+        table.add_string("Hello".to_string(), 3);
+        assert_eq!(table.string_offset("Hello"), Some(3));
+        assert_eq!(table.string_offset("test"), None);
+
+        table.set_constant("SIZE".to_string(), Expression::Number(1));
+        table.set_constant("SIZE".to_string(), Expression::Number(2));
+        assert_eq!(table.constant("SIZE"), Some(Expression::Number(2)));
+        assert_eq!(table.constant("test"), None);
+    }
+}
@@ -1,6 +1,7 @@
+use super::expression::Expression;
 use crate::vm::instruction::Opcode;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
@@ -8,4 +9,8 @@ pub enum Token {
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    String { value: String },
+    Float { bits: u64 }, // `#1.5` as f64 bits, keeps `Token` Eq. Interned into read-only data
+    Expression { expr: Expression }, // Evaluated by the assembler into `Number`
+    Identifier { name: String }, // `.equ` / `.set` constant name
 }
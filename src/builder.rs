@@ -0,0 +1,756 @@
+/*
+Emits bytecode without going through text assembly:
+
+let program = ProgramBuilder::new()
+    .load(0, 0)
+    .load(1, 10)
+    .label("loop")
+    .inc(0)
+    .jlt(0, 1, "loop")
+    .hlt()
+    .build()?;
+
+Labels may be used before they are declared, targets are patched by `build`. Every opcode has a
+typed method named after its mnemonic, `emit` takes any opcode with its operands
+*/
+
+use std::fmt;
+
+use crate::vm::instruction::{Opcode, OperandKind};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Operand {
+    Register(u8),
+    Immediate(i32),
+    Label(String), // Code offset of the label, patched by `build`
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BuilderError {
+    OperandMismatch { opcode: Opcode },
+    InvalidRegister { opcode: Opcode, register: u8 },
+    ImmediateOutOfRange { opcode: Opcode, value: i32 },
+    DuplicateLabel { name: String },
+    UnknownLabel { name: String },
+    LabelOutOfRange { name: String, offset: usize },
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuilderError::OperandMismatch { opcode } => {
+                write!(
+                    f,
+                    "Operands do not match {:?} {:?}",
+                    opcode,
+                    opcode.operands()
+                )
+            }
+            BuilderError::InvalidRegister { opcode, register } => {
+                write!(f, "Register ${} does not exist, in {:?}", register, opcode)
+            }
+            BuilderError::ImmediateOutOfRange { opcode, value } => {
+                write!(
+                    f,
+                    "Number {} does not fit into 16bits, in {:?}",
+                    value, opcode
+                )
+            }
+            BuilderError::DuplicateLabel { name } => {
+                write!(f, "Label `{}` is already declared", name)
+            }
+            BuilderError::UnknownLabel { name } => write!(f, "Unknown label `{}`", name),
+            BuilderError::LabelOutOfRange { name, offset } => {
+                write!(f, "Label `{}` at {} does not fit into 16bits", name, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuilderError {}
+
+#[derive(Debug, Default)]
+pub struct ProgramBuilder {
+    bytes: Vec<u8>,
+    labels: Vec<(String, usize)>, // Declared labels and their code offsets
+    patches: Vec<(usize, String)>, // Offsets of 16 bits operands waiting for a label
+    error: Option<BuilderError>,  // First error, reported by `build`
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    /// Bytecode with patched labels, or the first error found while building
+    pub fn build(&self) -> Result<Vec<u8>, BuilderError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        let mut bytes = self.bytes.clone();
+        for (position, name) in &self.patches {
+            let offset = match self.labels.iter().find(|(label, _)| label == name) {
+                Some((_, offset)) => *offset,
+                None => return Err(BuilderError::UnknownLabel { name: name.clone() }),
+            };
+            if offset > u16::MAX as usize {
+                return Err(BuilderError::LabelOutOfRange {
+                    name: name.clone(),
+                    offset,
+                });
+            }
+            bytes[*position..*position + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Code offset of the next instruction
+    pub fn offset(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Marks the next instruction
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        if self.labels.iter().any(|(label, _)| label == name) {
+            self.fail(BuilderError::DuplicateLabel {
+                name: name.to_string(),
+            });
+        } else {
+            self.labels.push((name.to_string(), self.bytes.len()));
+        }
+        self
+    }
+
+    /// Any instruction. Operands are checked against `Opcode::operands`
+    pub fn emit(&mut self, opcode: Opcode, operands: &[Operand]) -> &mut Self {
+        let kinds = opcode.operands();
+        if opcode == Opcode::IGL || kinds.len() != operands.len() {
+            self.fail(BuilderError::OperandMismatch { opcode });
+            return self;
+        }
+        let mut encoded = vec![opcode as u8];
+        let mut patches = vec![];
+        for (kind, operand) in kinds.iter().zip(operands) {
+            match (kind, operand) {
+                (OperandKind::Register, Operand::Register(register))
+                | (OperandKind::FloatRegister, Operand::Register(register)) => {
+                    if *register as usize >= kind.register_count() {
+                        self.fail(BuilderError::InvalidRegister {
+                            opcode,
+                            register: *register,
+                        });
+                        return self;
+                    }
+                    encoded.push(*register);
+                }
+                (OperandKind::Immediate | OperandKind::Target, Operand::Immediate(value)) => {
+                    // Same range as the assembler: signed or unsigned 16 bits
+                    if !(i16::MIN as i32..=u16::MAX as i32).contains(value) {
+                        self.fail(BuilderError::ImmediateOutOfRange {
+                            opcode,
+                            value: *value,
+                        });
+                        return self;
+                    }
+                    encoded.extend_from_slice(&(*value as u16).to_be_bytes());
+                }
+                (OperandKind::Immediate | OperandKind::Target, Operand::Label(name)) => {
+                    patches.push((self.bytes.len() + encoded.len(), name.clone()));
+                    encoded.extend_from_slice(&[0, 0]);
+                }
+                _ => {
+                    self.fail(BuilderError::OperandMismatch { opcode });
+                    return self;
+                }
+            }
+        }
+        self.bytes.append(&mut encoded);
+        self.patches.append(&mut patches);
+        self
+    }
+
+    fn fail(&mut self, error: BuilderError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    fn r(&mut self, opcode: Opcode, a: u8) -> &mut Self {
+        self.emit(opcode, &[Operand::Register(a)])
+    }
+
+    fn rr(&mut self, opcode: Opcode, a: u8, b: u8) -> &mut Self {
+        self.emit(opcode, &[Operand::Register(a), Operand::Register(b)])
+    }
+
+    fn rrr(&mut self, opcode: Opcode, a: u8, b: u8, c: u8) -> &mut Self {
+        let operands = [
+            Operand::Register(a),
+            Operand::Register(b),
+            Operand::Register(c),
+        ];
+        self.emit(opcode, &operands)
+    }
+
+    fn rir(&mut self, opcode: Opcode, a: u8, value: i32, c: u8) -> &mut Self {
+        let operands = [
+            Operand::Register(a),
+            Operand::Immediate(value),
+            Operand::Register(c),
+        ];
+        self.emit(opcode, &operands)
+    }
+
+    fn rrl(&mut self, opcode: Opcode, a: u8, b: u8, label: &str) -> &mut Self {
+        let operands = [
+            Operand::Register(a),
+            Operand::Register(b),
+            Operand::Label(label.to_string()),
+        ];
+        self.emit(opcode, &operands)
+    }
+
+    pub fn load(&mut self, register: u8, value: i32) -> &mut Self {
+        self.emit(
+            Opcode::LOAD,
+            &[Operand::Register(register), Operand::Immediate(value)],
+        )
+    }
+
+    /// `LOAD $r @label`
+    pub fn load_label(&mut self, register: u8, label: &str) -> &mut Self {
+        self.emit(
+            Opcode::LOAD,
+            &[
+                Operand::Register(register),
+                Operand::Label(label.to_string()),
+            ],
+        )
+    }
+
+    pub fn add(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::ADD, a, b, result)
+    }
+
+    pub fn sub(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SUB, a, b, result)
+    }
+
+    pub fn mul(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::MUL, a, b, result)
+    }
+
+    pub fn div(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::DIV, a, b, result)
+    }
+
+    pub fn modulo(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::MOD, a, b, result)
+    }
+
+    /// Like `add`, faults with IntegerOverflow instead of wrapping
+    pub fn addt(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::ADDT, a, b, result)
+    }
+
+    pub fn subt(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SUBT, a, b, result)
+    }
+
+    pub fn mult(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::MULT, a, b, result)
+    }
+
+    /// Remainder of the last `div`
+    pub fn mvr(&mut self, to: u8) -> &mut Self {
+        self.r(Opcode::MVR, to)
+    }
+
+    pub fn and(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::AND, a, b, result)
+    }
+
+    pub fn or(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::OR, a, b, result)
+    }
+
+    pub fn xor(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::XOR, a, b, result)
+    }
+
+    pub fn not(&mut self, a: u8, result: u8) -> &mut Self {
+        self.rr(Opcode::NOT, a, result)
+    }
+
+    pub fn shl(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SHL, a, b, result)
+    }
+
+    pub fn shr(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SHR, a, b, result)
+    }
+
+    pub fn sar(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SAR, a, b, result)
+    }
+
+    pub fn andi(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::ANDI, a, value, result)
+    }
+
+    pub fn ori(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::ORI, a, value, result)
+    }
+
+    pub fn xori(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::XORI, a, value, result)
+    }
+
+    pub fn shli(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::SHLI, a, value, result)
+    }
+
+    pub fn shri(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::SHRI, a, value, result)
+    }
+
+    pub fn sari(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::SARI, a, value, result)
+    }
+
+    pub fn addi(&mut self, a: u8, value: i32, result: u8) -> &mut Self {
+        self.rir(Opcode::ADDI, a, value, result)
+    }
+
+    pub fn inc(&mut self, register: u8) -> &mut Self {
+        self.r(Opcode::INC, register)
+    }
+
+    pub fn dec(&mut self, register: u8) -> &mut Self {
+        self.r(Opcode::DEC, register)
+    }
+
+    pub fn mov(&mut self, from: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::MOV, from, to)
+    }
+
+    /// `EQ $a $b $0`. The VM ignores the third register, it is encoded as $0
+    pub fn eq(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::EQ, a, b, 0)
+    }
+
+    /// `NEQ $a $b $0`, like `eq`
+    pub fn neq(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::NEQ, a, b, 0)
+    }
+
+    /// `GT $a $b $0`, like `eq`
+    pub fn gt(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::GT, a, b, 0)
+    }
+
+    /// `LT $a $b $0`, like `eq`
+    pub fn lt(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::LT, a, b, 0)
+    }
+
+    /// `GTQ $a $b $0`, like `eq`
+    pub fn gtq(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::GTQ, a, b, 0)
+    }
+
+    /// `LTQ $a $b $0`, like `eq`
+    pub fn ltq(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rrr(Opcode::LTQ, a, b, 0)
+    }
+
+    pub fn jmp(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JMP, target)
+    }
+
+    /// Forwards by $distance from the end of the instruction
+    pub fn jmpf(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JMPF, distance)
+    }
+
+    /// Backwards by $distance from the end of the instruction
+    pub fn jmpb(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JMPB, distance)
+    }
+
+    pub fn jeq(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JEQ, target)
+    }
+
+    pub fn jneq(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JNEQ, target)
+    }
+
+    pub fn jeqf(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JEQF, distance)
+    }
+
+    pub fn jeqb(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JEQB, distance)
+    }
+
+    pub fn jneqf(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JNEQF, distance)
+    }
+
+    pub fn jneqb(&mut self, distance: u8) -> &mut Self {
+        self.r(Opcode::JNEQB, distance)
+    }
+
+    pub fn jz(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JZ, target)
+    }
+
+    pub fn jnz(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JNZ, target)
+    }
+
+    pub fn jn(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JN, target)
+    }
+
+    pub fn jc(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JC, target)
+    }
+
+    pub fn jo(&mut self, target: u8) -> &mut Self {
+        self.r(Opcode::JO, target)
+    }
+
+    /// `LOAD $scratch @label` + `JMP $scratch`
+    pub fn jmp_label(&mut self, scratch: u8, label: &str) -> &mut Self {
+        self.load_label(scratch, label).jmp(scratch)
+    }
+
+    /// `LOAD $scratch @label` + `JEQ $scratch`
+    pub fn jeq_label(&mut self, scratch: u8, label: &str) -> &mut Self {
+        self.load_label(scratch, label).jeq(scratch)
+    }
+
+    /// `LOAD $scratch @label` + `JNEQ $scratch`
+    pub fn jneq_label(&mut self, scratch: u8, label: &str) -> &mut Self {
+        self.load_label(scratch, label).jneq(scratch)
+    }
+
+    pub fn jgt(&mut self, a: u8, b: u8, label: &str) -> &mut Self {
+        self.rrl(Opcode::JGT, a, b, label)
+    }
+
+    pub fn jlt(&mut self, a: u8, b: u8, label: &str) -> &mut Self {
+        self.rrl(Opcode::JLT, a, b, label)
+    }
+
+    pub fn jgtq(&mut self, a: u8, b: u8, label: &str) -> &mut Self {
+        self.rrl(Opcode::JGTQ, a, b, label)
+    }
+
+    pub fn jltq(&mut self, a: u8, b: u8, label: &str) -> &mut Self {
+        self.rrl(Opcode::JLTQ, a, b, label)
+    }
+
+    pub fn aloc(&mut self, register: u8) -> &mut Self {
+        self.r(Opcode::ALOC, register)
+    }
+
+    /// Float registers. `offset` is the read-only data offset of an 8 bytes big-endian f64
+    pub fn loadf(&mut self, register: u8, offset: u16) -> &mut Self {
+        self.emit(
+            Opcode::LOADF,
+            &[
+                Operand::Register(register),
+                Operand::Immediate(offset as i32),
+            ],
+        )
+    }
+
+    pub fn addf(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::ADDF, a, b, result)
+    }
+
+    pub fn subf(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::SUBF, a, b, result)
+    }
+
+    pub fn mulf(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::MULF, a, b, result)
+    }
+
+    pub fn divf(&mut self, a: u8, b: u8, result: u8) -> &mut Self {
+        self.rrr(Opcode::DIVF, a, b, result)
+    }
+
+    pub fn eqf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::EQF, a, b)
+    }
+
+    pub fn neqf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::NEQF, a, b)
+    }
+
+    pub fn gtf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::GTF, a, b)
+    }
+
+    pub fn ltf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::LTF, a, b)
+    }
+
+    pub fn gtqf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::GTQF, a, b)
+    }
+
+    pub fn ltqf(&mut self, a: u8, b: u8) -> &mut Self {
+        self.rr(Opcode::LTQF, a, b)
+    }
+
+    /// Integer register `from` to float register `to`
+    pub fn itof(&mut self, from: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::ITOF, from, to)
+    }
+
+    /// Float register `from` to integer register `to`
+    pub fn ftoi(&mut self, from: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::FTOI, from, to)
+    }
+
+    pub fn syscall(&mut self, number: u16) -> &mut Self {
+        self.emit(Opcode::SYSCALL, &[Operand::Immediate(number as i32)])
+    }
+
+    pub fn ldb(&mut self, address: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::LDB, address, to)
+    }
+
+    pub fn stb(&mut self, value: u8, address: u8) -> &mut Self {
+        self.rr(Opcode::STB, value, address)
+    }
+
+    pub fn native(&mut self, id: u16) -> &mut Self {
+        self.emit(Opcode::NATIVE, &[Operand::Immediate(id as i32)])
+    }
+
+    /// `SPAWN $id @label`
+    pub fn spawn(&mut self, id: u8, label: &str) -> &mut Self {
+        self.emit(
+            Opcode::SPAWN,
+            &[Operand::Register(id), Operand::Label(label.to_string())],
+        )
+    }
+
+    // `yield` is a keyword
+    pub fn yield_thread(&mut self) -> &mut Self {
+        self.emit(Opcode::YIELD, &[])
+    }
+
+    pub fn join(&mut self, id: u8) -> &mut Self {
+        self.r(Opcode::JOIN, id)
+    }
+
+    pub fn send(&mut self, channel: u8, value: u8) -> &mut Self {
+        self.rr(Opcode::SEND, channel, value)
+    }
+
+    pub fn recv(&mut self, channel: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::RECV, channel, to)
+    }
+
+    pub fn ldw(&mut self, address: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::LDW, address, to)
+    }
+
+    pub fn stw(&mut self, value: u8, address: u8) -> &mut Self {
+        self.rr(Opcode::STW, value, address)
+    }
+
+    pub fn cas(&mut self, address: u8, expected: u8, new: u8) -> &mut Self {
+        self.rrr(Opcode::CAS, address, expected, new)
+    }
+
+    pub fn fadd(&mut self, address: u8, value: u8, to: u8) -> &mut Self {
+        self.rrr(Opcode::FADD, address, value, to)
+    }
+
+    pub fn fence(&mut self) -> &mut Self {
+        self.emit(Opcode::FENCE, &[])
+    }
+
+    pub fn new_object(&mut self, size: u8, to: u8) -> &mut Self {
+        self.rr(Opcode::NEW, size, to)
+    }
+
+    pub fn free(&mut self, address: u8) -> &mut Self {
+        self.r(Opcode::FREE, address)
+    }
+
+    pub fn gc(&mut self) -> &mut Self {
+        self.emit(Opcode::GC, &[])
+    }
+
+    pub fn push(&mut self, register: u8) -> &mut Self {
+        self.r(Opcode::PUSH, register)
+    }
+
+    pub fn pop(&mut self, to: u8) -> &mut Self {
+        self.r(Opcode::POP, to)
+    }
+
+    pub fn hlt(&mut self) -> &mut Self {
+        self.emit(Opcode::HLT, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_build_with_forward_and_backward_labels() {
+        let program = ProgramBuilder::new()
+            .load(0, 0)
+            .load(1, 3)
+            .label("loop")
+            .inc(0)
+            .jlt(0, 1, "loop")
+            .jmp_label(5, "end")
+            .load(2, 99)
+            .label("end")
+            .hlt()
+            .build()
+            .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                0, 0, 0, 0, //
+                0, 1, 0, 3, //
+                39, 0, // loop: 8
+                23, 0, 1, 0, 8, //
+                0, 5, 0, 25, //
+                6, 5, //
+                0, 2, 0, 99, //
+                5,  // end: 25
+            ]
+        );
+
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.run();
+        assert_eq!(vm.register(0), 3);
+        assert_eq!(vm.register(2), 0);
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let result = ProgramBuilder::new().jeq_label(3, "nowhere").build();
+        assert_eq!(
+            result,
+            Err(BuilderError::UnknownLabel {
+                name: "nowhere".to_string()
+            })
+        );
+
+        let result = ProgramBuilder::new().label("a").hlt().label("a").build();
+        assert_eq!(
+            result,
+            Err(BuilderError::DuplicateLabel {
+                name: "a".to_string()
+            })
+        );
+
+        let result = ProgramBuilder::new().add(0, 1, 6).load(0, 70000).build();
+        assert_eq!(
+            result,
+            Err(BuilderError::InvalidRegister {
+                opcode: Opcode::ADD,
+                register: 6
+            })
+        );
+
+        let result = ProgramBuilder::new().load(0, 70000).build();
+        assert_eq!(
+            result,
+            Err(BuilderError::ImmediateOutOfRange {
+                opcode: Opcode::LOAD,
+                value: 70000
+            })
+        );
+
+        let result = ProgramBuilder::new()
+            .emit(Opcode::JMP, &[Operand::Immediate(1)])
+            .build();
+        assert_eq!(
+            result,
+            Err(BuilderError::OperandMismatch {
+                opcode: Opcode::JMP
+            })
+        );
+    }
+
+    #[test]
+    fn test_emit_matches_assembler() {
+        let program = ProgramBuilder::new()
+            .addi(0, -5, 1)
+            .emit(
+                Opcode::LOADF,
+                &[Operand::Register(2), Operand::Immediate(8)],
+            )
+            .syscall(1)
+            .build()
+            .unwrap();
+        let source = "addi $0 #-5 $1\nloadf $2 #8\nsyscall #1";
+        let image = crate::assembler::Assembler::new().assemble(source).unwrap();
+        assert_eq!(&image[crate::cmelf::CMELF_HEADER_LENGTH..], &program[..]);
+    }
+
+    #[test]
+    fn test_typed_methods_match_assembler() {
+        let program = ProgramBuilder::new()
+            .eq(1, 2)
+            .gtq(1, 2)
+            .ltq(1, 2)
+            .jmpf(3)
+            .jneqb(3)
+            .jz(4)
+            .and(0, 1, 2)
+            .not(0, 1)
+            .sari(0, 4, 2)
+            .addt(0, 1, 2)
+            .mvr(3)
+            .loadf(0, 8)
+            .divf(0, 1, 2)
+            .ltqf(0, 1)
+            .itof(1, 2)
+            .ftoi(2, 1)
+            .ldb(0, 1)
+            .stb(1, 0)
+            .build()
+            .unwrap();
+        let source = "
+            eq $1 $2 $0
+            gtq $1 $2 $0
+            ltq $1 $2 $0
+            jmpf $3
+            jneqb $3
+            jz $4
+            and $0 $1 $2
+            not $0 $1
+            sari $0 #4 $2
+            addt $0 $1 $2
+            mvr $3
+            loadf $0 #8
+            divf $0 $1 $2
+            ltqf $0 $1
+            itof $1 $2
+            ftoi $2 $1
+            ldb $0 $1
+            stb $1 $0
+        ";
+        let image = crate::assembler::Assembler::new().assemble(source).unwrap();
+        assert_eq!(&image[crate::cmelf::CMELF_HEADER_LENGTH..], &program[..]);
+    }
+}
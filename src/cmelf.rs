@@ -4,4 +4,109 @@
 
 CMELF Header: 4 bytes + 1 byte for version
 
+| magic (4) | version (1) | ro_data length (4) | padding up to 64 | ro_data | code |
+
 */
+
+use std::fmt;
+
+pub const CMELF_MAGIC: [u8; 4] = [0x7F, b'C', b'M', b'E'];
+pub const CMELF_VERSION: u8 = 1;
+pub const CMELF_HEADER_LENGTH: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CmelfError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion { version: u8 },
+    BadRoDataLength { length: usize },
+}
+
+impl fmt::Display for CmelfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CmelfError::TooShort => write!(f, "CMELF image is shorter than its header"),
+            CmelfError::BadMagic => write!(f, "CMELF magic number not found"),
+            CmelfError::UnsupportedVersion { version } => {
+                write!(f, "Unsupported CMELF version {}", version)
+            }
+            CmelfError::BadRoDataLength { length } => {
+                write!(f, "Read-only data length {} exceeds the image", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CmelfError {}
+
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Cmelf {
+    pub ro_data: Vec<u8>, // Read-only data segment. Addressed by `.data` labels
+    pub code: Vec<u8>,    // Bytecode. Addressed by `.code` labels
+}
+
+impl Cmelf {
+    pub fn new(ro_data: Vec<u8>, code: Vec<u8>) -> Cmelf {
+        Cmelf { ro_data, code }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(CMELF_HEADER_LENGTH + self.ro_data.len() + self.code.len());
+        bytes.extend_from_slice(&CMELF_MAGIC);
+        bytes.push(CMELF_VERSION);
+        bytes.extend_from_slice(&(self.ro_data.len() as u32).to_be_bytes());
+        bytes.resize(CMELF_HEADER_LENGTH, 0);
+        bytes.extend_from_slice(&self.ro_data);
+        bytes.extend_from_slice(&self.code);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cmelf, CmelfError> {
+        if bytes.len() < CMELF_HEADER_LENGTH {
+            return Err(CmelfError::TooShort);
+        }
+        if bytes[0..4] != CMELF_MAGIC {
+            return Err(CmelfError::BadMagic);
+        }
+        if bytes[4] != CMELF_VERSION {
+            return Err(CmelfError::UnsupportedVersion { version: bytes[4] });
+        }
+        let length = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) as usize;
+        let code_start = CMELF_HEADER_LENGTH
+            .checked_add(length)
+            .filter(|end| *end <= bytes.len())
+            .ok_or(CmelfError::BadRoDataLength { length })?;
+
+        Ok(Cmelf {
+            ro_data: bytes[CMELF_HEADER_LENGTH..code_start].to_vec(),
+            code: bytes[code_start..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmelf_roundtrip() {
+        let cmelf = Cmelf::new(vec![72, 105, 0], vec![5]);
+        let bytes = cmelf.to_bytes();
+        assert_eq!(bytes.len(), CMELF_HEADER_LENGTH + 4);
+        assert_eq!(Cmelf::from_bytes(&bytes), Ok(cmelf));
+    }
+
+    #[test]
+    fn test_cmelf_errors() {
+        assert_eq!(Cmelf::from_bytes(&[0; 8]), Err(CmelfError::TooShort));
+        assert_eq!(Cmelf::from_bytes(&[0; 64]), Err(CmelfError::BadMagic));
+
+        let mut bytes = Cmelf::default().to_bytes();
+        bytes[8] = 1;
+        assert_eq!(
+            Cmelf::from_bytes(&bytes),
+            Err(CmelfError::BadRoDataLength { length: 1 })
+        );
+    }
+}
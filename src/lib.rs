@@ -0,0 +1,43 @@
+#![deny(clippy::all)]
+//! Register based virtual machine with an assembler for its bytecode.
+//!
+//! - [`Assembler`] turns assembly source into a CMELF image (header + read-only data + code)
+//! - [`VM`] loads the image with [`VM::load_cmelf`] and runs it
+//! - [`ProgramBuilder`] emits bytecode from Rust with typed methods and patched labels
+//! - [`Repl`] runs single instructions and debug directives (`,registers`, `,program`, ...)
+//!
+//! ```
+//! use cm_vm::{Assembler, VM};
+//!
+//! let image = Assembler::new()
+//!     .assemble("load $0 #20\nload $1 #22\nadd $0 $1 $2\nhlt")
+//!     .unwrap();
+//! let mut vm = VM::new();
+//! vm.load_cmelf(&image).unwrap();
+//! vm.run();
+//! assert_eq!(vm.register(2), 42);
+//! assert!(vm.fault().is_none());
+//! ```
+
+pub mod assembler;
+pub mod builder;
+pub mod cmelf;
+pub mod repl;
+pub mod scheduler;
+pub mod vm;
+
+pub use assembler::{assembler_errors::AssemblerError, diagnostic::AssemblerDiagnostic, Assembler};
+pub use builder::{BuilderError, Operand, ProgramBuilder};
+pub use cmelf::{Cmelf, CmelfError};
+pub use repl::Repl;
+pub use scheduler::{Outcome, ProcessStatus, Scheduler};
+pub use vm::{
+    channel::Channels,
+    fault::VmFault,
+    flags::Flags,
+    gc::HeapStats,
+    instruction::{Opcode, OperandKind},
+    io::{BufferedIo, HostIo, StdIo},
+    verifier::{verify, VerifyError},
+    NativeFunction, RunState, VmConfig, VM,
+};
@@ -1,10 +1,6 @@
 #![deny(clippy::all)]
 
-mod assembler;
-mod repl;
-mod vm;
-
-use repl::Repl;
+use cm_vm::Repl;
 use std::io::{self, Write};
 
 use log::LevelFilter;
@@ -20,6 +16,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     main_program(&mut repl)
     // main_hex_program(&mut repl)
     // main_hex_lopp(&mut repl)
+    // main_assembly(&mut repl)
 }
 
 #[allow(dead_code)]
@@ -54,6 +51,24 @@ fn main_hex_program(repl: &mut Repl) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[allow(dead_code)]
+fn main_assembly(repl: &mut Repl) -> Result<(), Box<dyn std::error::Error>> {
+    // Whole source: sections, labels and read-only data
+    let source = "
+        .data
+        hello: .asciiz \"Hello\"
+        table: .byte #1 #2 #3
+        .code
+        load $0 @hello
+        load $1 @table
+        hlt
+    ";
+
+    repl.run_assembly(source);
+    repl.run_program(vec![",registers", ",ro_data"]);
+    Ok(())
+}
+
 fn main_program(repl: &mut Repl) -> Result<(), Box<dyn std::error::Error>> {
     // program
     #[rustfmt::skip]
@@ -72,7 +87,7 @@ fn main_program(repl: &mut Repl) -> Result<(), Box<dyn std::error::Error>> {
 
         "load $4 #4",
         "eq $3 $4 $4",
-        ",equal_flag",
+        ",flags",
         
         ",registers",
         ",commands",
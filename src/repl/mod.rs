@@ -2,13 +2,23 @@ use std::num::ParseIntError;
 
 use log::{debug, error, info};
 
-use crate::{assembler::Assembler, vm::VM};
+use crate::{
+    assembler::{symbols::SymbolTable, Assembler},
+    vm::VM,
+};
 
+/// Runs instructions one by one on its own VM. Lines starting with `,` are debug directives
 pub struct Repl {
     commands: Vec<String>,
     vm: VM,
 }
 
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Repl {
     pub fn new() -> Repl {
         Repl {
@@ -41,15 +51,47 @@ impl Repl {
         });
     }
 
+    /// Assembles a whole source (sections, labels, data) and runs it from the start
+    pub fn run_assembly(&mut self, source: &str) {
+        let mut assembler = Assembler::new();
+        match assembler.assemble(source) {
+            Ok(cmelf) => match self.vm.load_cmelf(&cmelf) {
+                Ok(()) => {
+                    self.vm.run();
+                    if let Some(fault) = self.vm.fault() {
+                        error!("Program stopped: {}", fault);
+                    }
+                    if let Some(code) = self.vm.exit_code() {
+                        info!("Program exited with code {}", code);
+                    }
+                }
+                Err(e) => error!("Unable to load program: {}", e),
+            },
+            Err(errors) => {
+                for e in errors {
+                    error!("{}", e);
+                }
+            }
+        }
+    }
+
     pub fn execute_command(&mut self, command: &str) {
         let parsed_program = Assembler::parse_instruction(command);
 
         match parsed_program {
+            Ok(instruction) if !instruction.is_opcode() => {
+                info!("Directives and labels have no effect in the REPL");
+            }
             Ok(instruction) => {
-                let bytes_command = instruction.to_bytes();
+                if let Err(e) = instruction.check_operands() {
+                    error!("{}", e);
+                    return;
+                }
+                let symbols = SymbolTable::new();
+                let bytes_command = instruction.to_bytes(&symbols);
 
                 // Dont need this part. For test
-                let hex_command = instruction.to_hex();
+                let hex_command = instruction.to_hex(&symbols);
                 debug!("Hex: {}", hex_command.join(" "));
                 //
 
@@ -59,8 +101,8 @@ impl Repl {
                 }
                 self.vm.run_once();
             }
-            Err(_) => {
-                error!("Unable to decode command string")
+            Err(e) => {
+                error!("Unable to decode command string\n{}", e)
             }
         }
     }
@@ -88,7 +130,7 @@ impl Repl {
         match command {
             ",program" => {
                 info!("Listing instructions currently in VM's program vector:");
-                for instruction in &self.vm.program {
+                for instruction in self.vm.program() {
                     info!("{}", instruction);
                 }
                 info!("End of Program Listing");
@@ -100,11 +142,25 @@ impl Repl {
             }
             ",registers" => {
                 info!("Listing registers and all contents:");
-                info!("{:#?}", self.vm.registers);
+                info!("{:#?}", self.vm.registers());
+                info!("Remainder: {}", self.vm.remainder());
                 info!("End of Register Listing")
             }
-            ",equal_flag" => {
-                info!("Equal flag: {}", self.vm.equal_flag);
+            ",fregisters" => {
+                info!("Listing float registers and all contents:");
+                info!("{:#?}", self.vm.float_registers());
+                info!("End of Float Register Listing")
+            }
+            ",ro_data" => {
+                info!("Listing read-only data section:");
+                info!("{:?}", self.vm.ro_data());
+                info!("End of Read-only Data Listing");
+            }
+            ",flags" => {
+                info!("Flags: {}", self.vm.flags());
+            }
+            ",heap" => {
+                info!("Heap: {}", self.vm.heap_stats());
             }
             ",quit" => {
                 info!("Farewell! Have a great day!");
@@ -0,0 +1,239 @@
+use crate::vm::{fault::VmFault, RunState, VM};
+
+/// State of a VM hosted by a [`Scheduler`]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ProcessStatus {
+    /// Waiting for its next time slice
+    Ready,
+    /// HLT or the end of the program
+    Halted,
+    /// EXIT syscall
+    Exited(i32),
+    Faulted(VmFault),
+    /// Used up the scheduler's instruction limit
+    TimedOut,
+    /// Waiting in RECV while no VM could make progress, see [`crate::vm::channel::Channels`]
+    Blocked,
+}
+
+/// Per VM report of [`Scheduler::run`]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Outcome {
+    pub id: usize,
+    pub status: ProcessStatus,
+    pub instructions: u64,
+    pub slices: u64, // Time slices the VM was given
+}
+
+struct Process {
+    vm: VM,
+    status: ProcessStatus,
+    slices: u64,
+}
+
+/// Round-robin scheduler of independent VMs, ex. one per student submission.
+/// Each turn runs one VM for at most `time_slice` instructions
+pub struct Scheduler {
+    time_slice: u64,
+    instruction_limit: Option<u64>, // Per VM, stops runaway programs
+    processes: Vec<Process>,
+}
+
+impl Scheduler {
+    pub fn new(time_slice: u64) -> Scheduler {
+        Scheduler {
+            time_slice: time_slice.max(1),
+            instruction_limit: None,
+            processes: vec![],
+        }
+    }
+
+    /// VMs executing more than `limit` instructions are stopped as [`ProcessStatus::TimedOut`]
+    pub fn set_instruction_limit(&mut self, limit: u64) {
+        self.instruction_limit = Some(limit);
+    }
+
+    /// Adds a VM with its program loaded. Returns its id, ids count from 0 in spawn order
+    pub fn spawn(&mut self, vm: VM) -> usize {
+        self.processes.push(Process {
+            vm,
+            status: ProcessStatus::Ready,
+            slices: 0,
+        });
+        self.processes.len() - 1
+    }
+
+    pub fn vm(&self, id: usize) -> Option<&VM> {
+        self.processes.get(id).map(|process| &process.vm)
+    }
+
+    pub fn status(&self, id: usize) -> Option<&ProcessStatus> {
+        self.processes.get(id).map(|process| &process.status)
+    }
+
+    /// Gives one time slice to every ready VM. Returns false once none is ready.
+    /// A VM blocked in RECV stays ready until a round in which no VM made progress
+    pub fn step(&mut self) -> bool {
+        let (time_slice, limit) = (self.time_slice, self.instruction_limit);
+        let mut progress = false;
+        for process in self
+            .processes
+            .iter_mut()
+            .filter(|process| process.status == ProcessStatus::Ready)
+        {
+            let executed = process.vm.instructions_executed();
+            let fuel = limit.map_or(time_slice, |limit| {
+                time_slice.min(limit.saturating_sub(executed))
+            });
+            process.slices += 1;
+            let state = process.vm.run_for(fuel);
+            progress |= state != RunState::Blocked || process.vm.instructions_executed() > executed;
+            process.status = match state {
+                RunState::Stopped => match (process.vm.fault(), process.vm.exit_code()) {
+                    (Some(fault), _) => ProcessStatus::Faulted(fault.clone()),
+                    (None, Some(code)) => ProcessStatus::Exited(code),
+                    (None, None) => ProcessStatus::Halted,
+                },
+                RunState::OutOfFuel
+                    if limit.is_some_and(|limit| process.vm.instructions_executed() >= limit) =>
+                {
+                    ProcessStatus::TimedOut
+                }
+                RunState::OutOfFuel | RunState::Blocked => ProcessStatus::Ready,
+            };
+        }
+        if !progress {
+            for process in &mut self.processes {
+                if process.status == ProcessStatus::Ready {
+                    process.status = ProcessStatus::Blocked;
+                }
+            }
+        }
+        self.processes
+            .iter()
+            .any(|process| process.status == ProcessStatus::Ready)
+    }
+
+    /// Runs until every VM stopped
+    pub fn run(&mut self) -> Vec<Outcome> {
+        while self.step() {}
+        self.outcomes()
+    }
+
+    pub fn outcomes(&self) -> Vec<Outcome> {
+        self.processes
+            .iter()
+            .enumerate()
+            .map(|(id, process)| Outcome {
+                id,
+                status: process.status.clone(),
+                instructions: process.vm.instructions_executed(),
+                slices: process.slices,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+
+    fn vm(program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm
+    }
+
+    // INC $0 until it reaches `count`
+    fn counter(count: i32) -> VM {
+        let program = ProgramBuilder::new()
+            .load(1, count)
+            .label("loop")
+            .inc(0)
+            .jlt(0, 1, "loop")
+            .hlt()
+            .build()
+            .unwrap();
+        vm(program)
+    }
+
+    #[test]
+    fn test_interleaves_time_slices() {
+        let mut scheduler = Scheduler::new(10);
+        let long = scheduler.spawn(counter(100));
+        let short = scheduler.spawn(counter(3));
+        assert!(scheduler.step());
+        assert_eq!(scheduler.vm(long).unwrap().instructions_executed(), 10);
+        assert_eq!(scheduler.status(short), Some(&ProcessStatus::Halted));
+        assert_eq!(scheduler.status(long), Some(&ProcessStatus::Ready));
+
+        let outcomes = scheduler.run();
+        assert_eq!(scheduler.vm(long).unwrap().register(0), 100);
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome {
+                    id: 0,
+                    status: ProcessStatus::Halted,
+                    instructions: 202,
+                    slices: 21,
+                },
+                Outcome {
+                    id: 1,
+                    status: ProcessStatus::Halted,
+                    instructions: 8,
+                    slices: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unlimited_time_slice() {
+        let mut started = counter(3);
+        started.run_for(1);
+        let mut scheduler = Scheduler::new(u64::MAX);
+        let id = scheduler.spawn(started);
+        scheduler.run();
+        assert_eq!(scheduler.status(id), Some(&ProcessStatus::Halted));
+    }
+
+    #[test]
+    fn test_reports_outcomes() {
+        let mut scheduler = Scheduler::new(4);
+        scheduler.set_instruction_limit(50);
+        scheduler.spawn(vm(ProgramBuilder::new()
+            .load(0, 7)
+            .syscall(0)
+            .build()
+            .unwrap()));
+        scheduler.spawn(vm(ProgramBuilder::new().div(0, 1, 2).build().unwrap()));
+        // Falls off the end with $1 = 0, spins forever with $1 = 1
+        let spin = ProgramBuilder::new()
+            .label("spin")
+            .jlt(0, 1, "spin")
+            .build()
+            .unwrap();
+        scheduler.spawn(vm(spin.clone()));
+        let mut spinning = vm(spin);
+        spinning.set_register(1, 1);
+        scheduler.spawn(spinning);
+
+        let statuses: Vec<ProcessStatus> = scheduler
+            .run()
+            .into_iter()
+            .map(|outcome| outcome.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ProcessStatus::Exited(7),
+                ProcessStatus::Faulted(VmFault::DivisionByZero { pc: 0 }),
+                ProcessStatus::Halted,
+                ProcessStatus::TimedOut,
+            ]
+        );
+        assert_eq!(scheduler.vm(3).unwrap().instructions_executed(), 50);
+    }
+}
@@ -0,0 +1,181 @@
+use super::fault::VmFault;
+use super::VM;
+
+/*
+Memory model of the shared heap.
+
+Green threads switch only between instructions, so every instruction is atomic and all threads
+see heap writes in one total order, the order of execution (sequential consistency). A race is
+a sequence of instructions another thread can interleave with: `LDW` + `ADD` + `STW` may lose
+an update, `FADD` can't. `CAS` and `FADD` are single read-modify-write instructions; `FENCE`
+orders nothing more than any other instruction and exists so lock-free algorithms read the same
+as on real hardware.
+
+Words are 32 bits, big-endian like instruction operands, at any byte address. A word past the
+end of the heap faults with `InvalidAddress` and nothing is written.
+
+`VmConfig::interleave_seed` makes preemption points pseudo-random but reproducible, see
+`vm::thread`
+*/
+impl VM {
+    const WORD: usize = 4;
+
+    // Heap index of a whole word at `address`
+    fn word_index(&self, address: i32) -> Option<usize> {
+        let index = usize::try_from(address).ok()?;
+        (index.checked_add(VM::WORD)? <= self.heap.len()).then_some(index)
+    }
+
+    // Stops the VM on an invalid address
+    pub(super) fn load_word(&mut self, address: i32, instruction_pc: usize) -> Option<i32> {
+        match self.word_index(address) {
+            Some(index) => {
+                let bytes = &self.heap[index..index + VM::WORD];
+                Some(i32::from_be_bytes(bytes.try_into().unwrap()))
+            }
+            None => {
+                self.stop(VmFault::InvalidAddress {
+                    pc: instruction_pc,
+                    address: address as usize,
+                });
+                None
+            }
+        }
+    }
+
+    pub(super) fn store_word(&mut self, address: i32, value: i32, instruction_pc: usize) -> bool {
+        match self.word_index(address) {
+            Some(index) => {
+                self.heap[index..index + VM::WORD].copy_from_slice(&value.to_be_bytes());
+                false
+            }
+            None => self.stop(VmFault::InvalidAddress {
+                pc: instruction_pc,
+                address: address as usize,
+            }),
+        }
+    }
+
+    // `CAS $address $expected $new`
+    pub(super) fn compare_and_swap(
+        &mut self,
+        [address, expected, new]: [usize; 3],
+        instruction_pc: usize,
+    ) -> bool {
+        let address = self.registers[address];
+        let current = match self.load_word(address, instruction_pc) {
+            Some(current) => current,
+            None => return true,
+        };
+        self.flags.equal = current == self.registers[expected];
+        if !self.flags.equal {
+            self.registers[expected] = current;
+            return false;
+        }
+        self.store_word(address, self.registers[new], instruction_pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::tests::assembled_vm;
+    use crate::vm::{fault::VmFault, VmConfig, VM};
+
+    #[test]
+    fn test_word_and_atomic_opcodes() {
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #8
+            aloc $0
+            load $0 #1
+            load $1 #300
+            stw $1 $0
+            ldw $0 $2
+            load $3 #7
+            cas $0 $3 $1
+            mov $3 $4
+            load $1 #9
+            cas $0 $3 $1
+            fadd $0 $1 $5
+            fence
+            ldw $0 $3
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        assert_eq!(&vm.heap()[..5], &[0, 0, 0, 0, 18]);
+        assert_eq!(vm.register(2), 300);
+        assert_eq!(vm.register(4), 300); // Failed CAS loaded the current value
+        assert_eq!(vm.register(5), 9); // Value before FADD
+        assert_eq!(vm.register(3), 18);
+        assert!(vm.flags().equal);
+
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #4\naloc $0\nload $0 #1\nldw $0 $1",
+        );
+        vm.run();
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::InvalidAddress { pc: 10, address: 1 })
+        );
+    }
+
+    // Main and a worker add 1 to the word at 0, 50 times each. `increment` is the update
+    fn counter(increment: &str) -> String {
+        format!(
+            "load $0 #4
+            aloc $0
+            load $0 #0
+            load $1 #1
+            load $2 #0
+            load $3 #50
+            spawn $5 @count
+            count: {increment}
+            inc $2
+            jlt $2 $3 @count
+            load $4 #0
+            jgt $5 $4 @main
+            hlt
+            main: join $5
+            hlt"
+        )
+    }
+
+    fn interleaved(seed: u64, source: &str) -> VM {
+        let config = VmConfig {
+            interleave_seed: Some(seed),
+            ..VmConfig::default()
+        };
+        let mut vm = assembled_vm(config, source);
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_seeded_interleaving_reproduces_races() {
+        let racy = counter("ldw $0 $4\n            add $4 $1 $4\n            stw $4 $0");
+        let totals: Vec<i32> = (0..20)
+            .map(|seed| {
+                let vm = interleaved(seed, &racy);
+                assert_eq!(vm.fault(), None);
+                let mut word = [0; 4];
+                word.copy_from_slice(&vm.heap()[..4]);
+                i32::from_be_bytes(word)
+            })
+            .collect();
+        assert!(totals.iter().any(|&total| total < 100), "{:?}", totals);
+        // Same seed, same interleaving
+        let again = interleaved(3, &racy);
+        assert_eq!(&again.heap()[..4], &totals[3].to_be_bytes());
+        assert_eq!(
+            again.instructions_executed(),
+            interleaved(3, &racy).instructions_executed()
+        );
+
+        let atomic = counter("fadd $0 $1 $4");
+        for seed in 0..20 {
+            assert_eq!(&interleaved(seed, &atomic).heap()[..4], &[0, 0, 0, 100]);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::fault::VmFault;
+use super::VM;
+
+/// Host-side registry of message queues connecting VMs in one process.
+///
+/// Clones share the same channels: create them on the host, then give a clone to each VM
+/// with [`VM::connect_channels`]. Guest code uses the handles returned by
+/// [`Channels::create`]: `SEND $channel $value` queues a value, `RECV $channel $dst` takes the
+/// oldest one, or blocks the VM while the channel is empty. Channels are unbounded
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+    queues: Rc<RefCell<Vec<VecDeque<i32>>>>,
+}
+
+impl Channels {
+    pub fn new() -> Channels {
+        Channels::default()
+    }
+
+    /// New empty channel. Handles count from 0 in creation order, `None` once all 65536
+    /// handles are taken
+    pub fn create(&self) -> Option<u16> {
+        let mut queues = self.queues.borrow_mut();
+        let handle = u16::try_from(queues.len()).ok()?;
+        queues.push(VecDeque::new());
+        Some(handle)
+    }
+
+    /// Queues `value`, false if the channel does not exist
+    pub fn send(&self, channel: u16, value: i32) -> bool {
+        match self.queues.borrow_mut().get_mut(channel as usize) {
+            Some(queue) => {
+                queue.push_back(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Oldest value of the channel, `None` if it is empty or does not exist
+    pub fn try_recv(&self, channel: u16) -> Option<i32> {
+        self.queues
+            .borrow_mut()
+            .get_mut(channel as usize)
+            .and_then(|queue| queue.pop_front())
+    }
+
+    /// Values waiting in the channel, `None` if it does not exist
+    pub fn pending(&self, channel: u16) -> Option<usize> {
+        self.queues
+            .borrow()
+            .get(channel as usize)
+            .map(|queue| queue.len())
+    }
+}
+
+impl VM {
+    /// Gives SEND/RECV access to `channels`, a clone of the registry shared with other VMs
+    pub fn connect_channels(&mut self, channels: Channels) {
+        self.channels = Some(channels);
+    }
+
+    pub fn channels(&self) -> Option<&Channels> {
+        self.channels.as_ref()
+    }
+
+    /// True if the last run stopped in RECV on an empty channel. Running again retries it
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    // Handle of an existing channel
+    fn channel_handle(&self, channel: i32) -> Option<u16> {
+        let channels = self.channels.as_ref()?;
+        u16::try_from(channel)
+            .ok()
+            .filter(|&handle| channels.pending(handle).is_some())
+    }
+
+    pub(super) fn has_message(&self, channel: u16) -> bool {
+        self.channels
+            .as_ref()
+            .and_then(|channels| channels.pending(channel))
+            .is_some_and(|pending| pending > 0)
+    }
+
+    pub(super) fn send(&mut self, channel: i32, value: i32, instruction_pc: usize) -> bool {
+        let sent = u16::try_from(channel)
+            .ok()
+            .zip(self.channels.as_ref())
+            .is_some_and(|(handle, channels)| channels.send(handle, value));
+        if sent {
+            return false;
+        }
+        self.stop(VmFault::UnknownChannel {
+            pc: instruction_pc,
+            channel,
+        })
+    }
+
+    pub(super) fn receive(&mut self, channel: i32, register: usize, instruction_pc: usize) -> bool {
+        let handle = match self.channel_handle(channel) {
+            Some(handle) => handle,
+            None => {
+                return self.stop(VmFault::UnknownChannel {
+                    pc: instruction_pc,
+                    channel,
+                })
+            }
+        };
+        match self
+            .channels
+            .as_ref()
+            .and_then(|channels| channels.try_recv(handle))
+        {
+            Some(value) => {
+                self.registers[register] = value;
+                false
+            }
+            None => {
+                // Not executed yet, RECV runs again when the thread is resumed
+                self.pc = instruction_pc;
+                self.executed -= 1;
+                self.wait_for_message(handle, instruction_pc)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{ProcessStatus, Scheduler};
+    use crate::vm::tests::assembled_vm;
+    use crate::vm::{RunState, VmConfig};
+
+    fn vm(channels: &Channels, source: &str) -> VM {
+        let mut vm = assembled_vm(VmConfig::default(), source);
+        vm.connect_channels(channels.clone());
+        vm
+    }
+
+    // Receives from channel 0 until a 0, sums into $2 and replies on channel 1
+    const ADDER: &str = "
+        load $0 #0
+        load $3 #0
+        loop: recv $0 $1
+        add $2 $1 $2
+        jgt $1 $3 @loop
+        load $0 #1
+        send $0 $2
+        hlt
+    ";
+
+    // Sends 5, 4, .. 0 on channel 0, then waits for the sum on channel 1
+    const PRODUCER: &str = "
+        load $0 #0
+        load $1 #5
+        load $2 #0
+        loop: send $0 $1
+        dec $1
+        jgtq $1 $2 @loop
+        load $0 #1
+        recv $0 $3
+        hlt
+    ";
+
+    #[test]
+    fn test_channels_between_vms() {
+        let channels = Channels::new();
+        let (requests, replies) = (channels.create(), channels.create());
+        assert_eq!((requests, replies), (Some(0), Some(1)));
+
+        let mut scheduler = Scheduler::new(4);
+        let adder = scheduler.spawn(vm(&channels, ADDER));
+        let producer = scheduler.spawn(vm(&channels, PRODUCER));
+        scheduler.run();
+        assert_eq!(scheduler.status(adder), Some(&ProcessStatus::Halted));
+        assert_eq!(scheduler.status(producer), Some(&ProcessStatus::Halted));
+        assert_eq!(scheduler.vm(producer).unwrap().register(3), 15);
+        assert_eq!(channels.pending(0), Some(0));
+        assert_eq!(channels.pending(2), None);
+
+        let full = Channels::new();
+        for handle in 0..=u16::MAX {
+            assert_eq!(full.create(), Some(handle));
+        }
+        assert_eq!(full.create(), None);
+    }
+
+    #[test]
+    fn test_recv_blocks_until_sent() {
+        let channels = Channels::new();
+        channels.create();
+        let mut adder = vm(&channels, ADDER);
+        adder.run();
+        assert!(adder.is_blocked());
+        assert_eq!((adder.pc(), adder.instructions_executed()), (8, 2));
+
+        channels.send(0, 7);
+        assert_eq!(adder.run_for(100), RunState::Blocked);
+        assert_eq!(adder.register(2), 7);
+        channels.send(0, 0);
+        adder.run();
+        // No reply channel
+        assert_eq!(
+            adder.fault(),
+            Some(&VmFault::UnknownChannel { pc: 24, channel: 1 })
+        );
+
+        // Nobody ever sends
+        let lonely = Channels::new();
+        lonely.create();
+        let mut scheduler = Scheduler::new(10);
+        scheduler.spawn(vm(&lonely, "load $0 #0\nrecv $0 $1\nhlt"));
+        assert!(scheduler.step());
+        assert!(!scheduler.step());
+        assert_eq!(scheduler.status(0), Some(&ProcessStatus::Blocked));
+    }
+
+    #[test]
+    fn test_recv_switches_green_threads() {
+        let channels = Channels::new();
+        channels.create();
+        // The worker waits for main, main for the host
+        let source = "
+            load $0 #0
+            spawn $4 @worker
+            recv $0 $1
+            send $0 $1
+            join $4
+            hlt
+            worker: recv $0 $2
+            hlt
+        ";
+        let mut vm = vm(&channels, source);
+        vm.run();
+        assert!(vm.is_blocked());
+        channels.send(0, 42);
+        vm.run();
+        assert!(!vm.is_blocked());
+        assert_eq!(vm.fault(), None);
+        assert_eq!(vm.register(1), 42);
+        assert_eq!(channels.pending(0), Some(0));
+    }
+}
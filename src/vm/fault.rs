@@ -0,0 +1,99 @@
+use std::fmt;
+
+// Reason the VM stopped before HLT. `pc` is the offset of the faulting instruction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VmFault {
+    IllegalOpcode { pc: usize, opcode: u8 },
+    // Operands run past the end of the program
+    TruncatedInstruction { pc: usize },
+    // Register operand past `VM::REGISTERS`, or `VM::FLOAT_REGISTERS` for float operands
+    InvalidRegister { pc: usize, register: u8 },
+    DivisionByZero { pc: usize },
+    // JMPF/JMPB family with a negative distance or before the start of the program
+    InvalidJump { pc: usize, distance: i32 },
+    IntegerOverflow { pc: usize }, // Raised by trapping arithmetic: ADDT, SUBT, MULT
+    InvalidAddress { pc: usize, address: usize }, // Access past the end of memory
+    UnknownSyscall { pc: usize, number: u16 },
+    // NATIVE #id without `VM::register_native`
+    UnknownNative { pc: usize, id: u16 },
+    // JOIN on a thread id SPAWN did not return
+    UnknownThread { pc: usize, thread: i32 },
+    // Every thread is waiting in JOIN
+    Deadlock { pc: usize },
+    // SEND/RECV on a handle `Channels::create` did not return, or without `VM::connect_channels`
+    UnknownChannel { pc: usize, channel: i32 },
+    // NEW or ALOC with a negative size
+    InvalidAllocation { pc: usize, size: i32 },
+    // FREE of an address NEW did not return, or of a freed object
+    InvalidFree { pc: usize, address: i32 },
+    // PUSH on a full stack, `VM::STACK_SIZE` values per thread
+    StackOverflow { pc: usize },
+    // POP on an empty stack
+    StackUnderflow { pc: usize },
+    // Raised by a native function. `pc` is set by the VM, natives may leave it 0
+    Native { pc: usize, id: u16, message: String },
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmFault::IllegalOpcode { pc, opcode } => {
+                write!(f, "Illegal opcode {} at byte {}", opcode, pc)
+            }
+            VmFault::TruncatedInstruction { pc } => {
+                write!(f, "Truncated instruction at byte {}", pc)
+            }
+            VmFault::InvalidRegister { pc, register } => {
+                write!(f, "Invalid register ${} at byte {}", register, pc)
+            }
+            VmFault::InvalidJump { pc, distance } => {
+                write!(f, "Invalid relative jump by {} at byte {}", distance, pc)
+            }
+            VmFault::DivisionByZero { pc } => write!(f, "Division by zero at byte {}", pc),
+            VmFault::IntegerOverflow { pc } => write!(f, "Integer overflow at byte {}", pc),
+            VmFault::InvalidAddress { pc, address } => {
+                write!(f, "Invalid memory address {} at byte {}", address, pc)
+            }
+            VmFault::UnknownSyscall { pc, number } => {
+                write!(f, "Unknown syscall {} at byte {}", number, pc)
+            }
+            VmFault::UnknownNative { pc, id } => {
+                write!(
+                    f,
+                    "Native function {} is not registered, at byte {}",
+                    id, pc
+                )
+            }
+            VmFault::UnknownThread { pc, thread } => {
+                write!(f, "Unknown thread {} at byte {}", thread, pc)
+            }
+            VmFault::Deadlock { pc } => {
+                write!(f, "Deadlock, all threads are joining, at byte {}", pc)
+            }
+            VmFault::UnknownChannel { pc, channel } => {
+                write!(f, "Unknown channel {} at byte {}", channel, pc)
+            }
+            VmFault::InvalidAllocation { pc, size } => {
+                write!(f, "Invalid allocation of {} bytes at byte {}", size, pc)
+            }
+            VmFault::InvalidFree { pc, address } => {
+                write!(
+                    f,
+                    "Free of {} which is not an object, at byte {}",
+                    address, pc
+                )
+            }
+            VmFault::StackOverflow { pc } => write!(f, "Stack overflow at byte {}", pc),
+            VmFault::StackUnderflow { pc } => write!(f, "Pop from an empty stack at byte {}", pc),
+            VmFault::Native { pc, id, message } => {
+                write!(
+                    f,
+                    "Native function {} failed at byte {}: {}",
+                    id, pc, message
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmFault {}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+// Status register. Comparisons set `equal`, arithmetic sets the rest
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Flags {
+    pub equal: bool,    // Result of the last comparison operation
+    pub zero: bool,     // Last arithmetic result is 0
+    pub negative: bool, // Last arithmetic result is < 0
+    pub carry: bool, // Unsigned overflow: carry out of ADD, borrow of SUB. MUL sets it with `overflow`
+    pub overflow: bool, // Signed overflow, the result has wrapped
+}
+
+impl Flags {
+    // Updates zero/negative/carry/overflow for an arithmetic result. `equal` is untouched
+    pub fn set_arithmetic(&mut self, result: i32, carry: bool, overflow: bool) {
+        self.zero = result == 0;
+        self.negative = result < 0;
+        self.carry = carry;
+        self.overflow = overflow;
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "equal: {}, zero: {}, negative: {}, carry: {}, overflow: {}",
+            self.equal, self.zero, self.negative, self.carry, self.overflow
+        )
+    }
+}
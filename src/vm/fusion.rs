@@ -0,0 +1,297 @@
+use super::instruction::{Instruction, Opcode};
+use super::VM;
+
+/*
+Superinstructions: pairs of instructions executed by one dispatch of the predecoded loop.
+
+LOAD $r #value  + ADD/SUB/MUL $a $b $c
+EQ..LTQ $a $b   + JEQ/JNEQ $target
+LOAD $r #target + JMP/JEQ/JNEQ $r
+
+The second instruction keeps its own entry, so jumping to it runs it alone. Single stepping
+never uses fused entries, pc is only observed between instructions, same as without fusion
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum Superinstruction {
+    LoadArithmetic {
+        register: usize,
+        value: i32,
+        arithmetic: Opcode,
+        operands: [usize; 3],
+    },
+    CompareBranch {
+        comparison: Opcode,
+        operands: [usize; 2],
+        branch_if: bool, // Value of `flags.equal` taking the branch: JEQ true, JNEQ false
+        target: usize,
+    },
+    LoadBranch {
+        register: usize,
+        target: i32,
+        branch: Opcode,
+    },
+}
+
+// Entry of the predecoded program at an instruction offset
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum Decoded {
+    Single(Instruction),
+    Fused {
+        first: Instruction, // For single stepping
+        superinstruction: Superinstruction,
+        len: usize, // Bytes of both instructions
+    },
+}
+
+impl Decoded {
+    pub(super) fn first(&self) -> Instruction {
+        match *self {
+            Decoded::Single(instruction)
+            | Decoded::Fused {
+                first: instruction, ..
+            } => instruction,
+        }
+    }
+}
+
+impl Superinstruction {
+    fn fuse(first: &Instruction, second: &Instruction) -> Option<Superinstruction> {
+        let [a, b, c] = second.registers.map(usize::from);
+        let loaded = first.registers[0] as usize;
+        match (first.opcode, second.opcode) {
+            (Opcode::LOAD, Opcode::ADD | Opcode::SUB | Opcode::MUL) => {
+                Some(Superinstruction::LoadArithmetic {
+                    register: loaded,
+                    value: first.immediate as i32,
+                    arithmetic: second.opcode,
+                    operands: [a, b, c],
+                })
+            }
+            (
+                Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ,
+                Opcode::JEQ | Opcode::JNEQ,
+            ) => Some(Superinstruction::CompareBranch {
+                comparison: first.opcode,
+                operands: [first.registers[0] as usize, first.registers[1] as usize],
+                branch_if: second.opcode == Opcode::JEQ,
+                target: a,
+            }),
+            (Opcode::LOAD, Opcode::JMP | Opcode::JEQ | Opcode::JNEQ) if a == loaded => {
+                Some(Superinstruction::LoadBranch {
+                    register: loaded,
+                    target: first.immediate as i32,
+                    branch: second.opcode,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl VM {
+    // Replaces predecoded pairs by superinstructions
+    pub(super) fn fuse_superinstructions(&mut self) {
+        for offset in 0..self.decoded.len() {
+            let Some(Decoded::Single(first)) = self.decoded[offset] else {
+                continue;
+            };
+            let next = offset + first.byte_len();
+            let Some(Some(Decoded::Single(second))) = self.decoded.get(next) else {
+                continue;
+            };
+            if let Some(superinstruction) = Superinstruction::fuse(&first, second) {
+                self.decoded[offset] = Some(Decoded::Fused {
+                    first,
+                    superinstruction,
+                    len: first.byte_len() + second.byte_len(),
+                });
+            }
+        }
+    }
+
+    // Same effects as executing both instructions in order. None of them can fault
+    pub(super) fn execute_fused(&mut self, superinstruction: Superinstruction, len: usize) -> bool {
+        self.pc += len;
+        self.executed += 2;
+        match superinstruction {
+            Superinstruction::LoadArithmetic {
+                register,
+                value,
+                arithmetic,
+                operands: [a, b, c],
+            } => {
+                self.registers[register] = value;
+                let (value1, value2) = (self.registers[a], self.registers[b]);
+                self.registers[c] = match arithmetic {
+                    Opcode::ADD => self.add_with_flags(value1, value2),
+                    Opcode::SUB => self.sub_with_flags(value1, value2),
+                    _ => self.mul_with_flags(value1, value2),
+                };
+            }
+            Superinstruction::CompareBranch {
+                comparison,
+                operands: [a, b],
+                branch_if,
+                target,
+            } => {
+                let (value1, value2) = (self.registers[a], self.registers[b]);
+                self.flags.equal = match comparison {
+                    Opcode::EQ => value1 == value2,
+                    Opcode::NEQ => value1 != value2,
+                    Opcode::GT => value1 > value2,
+                    Opcode::LT => value1 < value2,
+                    Opcode::GTQ => value1 >= value2,
+                    _ => value1 <= value2,
+                };
+                if self.flags.equal == branch_if {
+                    self.pc = self.registers[target] as usize;
+                }
+            }
+            Superinstruction::LoadBranch {
+                register,
+                target,
+                branch,
+            } => {
+                self.registers[register] = target;
+                let taken = match branch {
+                    Opcode::JEQ => self.flags.equal,
+                    Opcode::JNEQ => !self.flags.equal,
+                    _ => true,
+                };
+                if taken {
+                    self.pc = target as usize;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::assembled_vm;
+    use crate::vm::VmConfig;
+
+    // $2 = 1 + 2 + ... + 100
+    const SUM: &[u8] = &[
+        0, 0, 0, 1, // LOAD $0 #1
+        0, 1, 0, 101, // LOAD $1 #101
+        0, 3, 0, 1, // loop: LOAD $3 #1
+        1, 2, 0, 2, // ADD $2 $0 $2
+        1, 0, 3, 0, // ADD $0 $3 $0
+        0, 4, 0, 8, // LOAD $4 @loop
+        12, 0, 1, 0, // LT $0 $1
+        15, 4, // JEQ $4
+        0, 5, 0, 36, // LOAD $5 @end
+        6, 5, // JMP $5
+        5, // end: HLT
+    ];
+
+    fn fused_vm(program: &[u8]) -> VM {
+        let mut vm = VM::with_config(VmConfig {
+            superinstructions: true,
+            ..VmConfig::default()
+        });
+        vm.load_program(program.to_vec());
+        vm
+    }
+
+    #[test]
+    fn test_fuse_pairs() {
+        let vm = fused_vm(SUM);
+        let fused = |offset: usize| match vm.decoded[offset] {
+            Some(Decoded::Fused {
+                superinstruction, ..
+            }) => Some(superinstruction),
+            _ => None,
+        };
+        assert!(matches!(
+            fused(8),
+            Some(Superinstruction::LoadArithmetic { register: 3, .. })
+        ));
+        assert!(matches!(
+            fused(24),
+            Some(Superinstruction::CompareBranch { target: 4, .. })
+        ));
+        assert_eq!(
+            fused(30),
+            Some(Superinstruction::LoadBranch {
+                register: 5,
+                target: 36,
+                branch: Opcode::JMP
+            })
+        );
+        assert_eq!(fused(12), None);
+        // Jumping to the second instruction of a pair runs it alone
+        assert!(matches!(vm.decoded[28], Some(Decoded::Single(_))));
+    }
+
+    #[test]
+    fn test_fused_run_matches_plain_run() {
+        let mut plain = VM::new();
+        plain.load_program(SUM.to_vec());
+        plain.run();
+        let mut fused = fused_vm(SUM);
+        fused.run();
+        assert_eq!(fused.register(2), 5050);
+        assert_eq!(fused.registers(), plain.registers());
+        assert_eq!(fused.flags(), plain.flags());
+        assert_eq!(fused.pc(), plain.pc());
+        assert_eq!(fused.instructions_executed(), plain.instructions_executed());
+    }
+
+    #[test]
+    fn test_fused_threads_match_plain_run() {
+        // Main and a worker add 1 to the word at 0 with LDW, LOAD + ADD, STW
+        let source = "
+            load $0 #4
+            aloc $0
+            load $0 #0
+            load $2 #0
+            load $3 #30
+            spawn $5 @count
+            count: ldw $0 $4
+            load $1 #1
+            add $4 $1 $4
+            stw $4 $0
+            inc $2
+            jlt $2 $3 @count
+            load $4 #0
+            jgt $5 $4 @main
+            hlt
+            main: join $5
+            hlt
+        ";
+        let run = |superinstructions: bool, slice: Option<u64>, seed: u64| {
+            let config = VmConfig {
+                superinstructions,
+                thread_time_slice: slice,
+                interleave_seed: Some(seed),
+                ..VmConfig::default()
+            };
+            let mut vm = assembled_vm(config, source);
+            vm.run();
+            assert_eq!(vm.fault(), None);
+            (vm.heap().to_vec(), vm.instructions_executed())
+        };
+        for slice in [Some(1), Some(2), None] {
+            for seed in 0..20 {
+                assert_eq!(run(true, slice, seed), run(false, slice, seed));
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_step_ignores_fusion() {
+        let mut plain = VM::new();
+        plain.load_program(SUM.to_vec());
+        let mut fused = fused_vm(SUM);
+        for _ in 0..50 {
+            plain.run_once();
+            fused.run_once();
+            assert_eq!(fused.pc(), plain.pc());
+            assert_eq!(fused.registers(), plain.registers());
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::fault::VmFault;
+use super::VM;
+
+/*
+Heap objects on top of the flat `VM::heap`.
+
+`NEW $size $dst` allocates a zeroed object and returns its heap address, which LDB/STB/LDW/STW
+use like any other address. `FREE $addr` gives it back, `GC` runs a mark-and-sweep collection.
+ALOC still appends raw bytes that are never reused or collected.
+
+Object headers (address and size) are kept in a table outside the heap, so a guest buffer
+overrun corrupts data, not the allocator. Freed blocks go to a free list, merged with their
+neighbours and reused first fit; a free block at the end of the heap shrinks it. Objects start
+after the first heap word, so address 0 (a zeroed register) is a null pointer.
+
+The collector is conservative: there is no type information, so any integer register or stack
+value of any live thread holding an address inside an object is a root, and so is every 32 bits
+word of a reachable object (at offsets 0, 4, 8, ..). Unreachable objects are freed. With
+`VmConfig::gc_threshold` NEW collects first once live objects use that many bytes
+*/
+#[derive(Debug, Clone, Default)]
+pub(super) struct Objects {
+    headers: BTreeMap<usize, usize>, // Size of each live object by address
+    free: BTreeMap<usize, usize>,    // Size of each free block by address
+    object_bytes: usize,
+    allocations: u64,
+    frees: u64, // FREE and collected objects
+    collections: u64,
+    collected: u64,
+}
+
+/// Heap usage reported by [`VM::heap_stats`] and the `,heap` REPL directive
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Length of [`VM::heap`]: objects, free blocks and ALOC bytes
+    pub heap_bytes: usize,
+    pub objects: usize,
+    pub object_bytes: usize,
+    /// Bytes of freed objects waiting to be reused
+    pub free_bytes: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub collections: u64,
+    /// Objects freed by the collector
+    pub collected: u64,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, {} objects ({} bytes), {} bytes free. {} allocations, {} frees, {} collections ({} objects collected)",
+            self.heap_bytes,
+            self.objects,
+            self.object_bytes,
+            self.free_bytes,
+            self.allocations,
+            self.frees,
+            self.collections,
+            self.collected
+        )
+    }
+}
+
+impl Objects {
+    // Address of the object containing `address`
+    fn containing(&self, address: i32) -> Option<usize> {
+        let address = usize::try_from(address).ok()?;
+        let (&start, &size) = self.headers.range(..=address).next_back()?;
+        (address < start + size).then_some(start)
+    }
+
+    // First fit in the free list, `None` if the heap has to grow
+    fn take_free(&mut self, size: usize) -> Option<usize> {
+        let (&address, &block) = self.free.iter().find(|(_, &block)| block >= size)?;
+        self.free.remove(&address);
+        if block > size {
+            self.free.insert(address + size, block - size);
+        }
+        Some(address)
+    }
+
+    // Merges the block with free neighbours. Returns the merged block
+    fn release(&mut self, mut address: usize, mut size: usize) -> (usize, usize) {
+        if let Some((&previous, &previous_size)) = self.free.range(..address).next_back() {
+            if previous + previous_size == address {
+                self.free.remove(&previous);
+                address = previous;
+                size += previous_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(address + size)) {
+            size += next_size;
+        }
+        self.free.insert(address, size);
+        (address, size)
+    }
+}
+
+// Bytes at the start of the heap never used by objects, address 0 is null
+const NULL_BYTES: usize = 4;
+
+impl VM {
+    pub fn heap_stats(&self) -> HeapStats {
+        HeapStats {
+            heap_bytes: self.heap.len(),
+            objects: self.objects.headers.len(),
+            object_bytes: self.objects.object_bytes,
+            free_bytes: self.objects.free.values().sum(),
+            allocations: self.objects.allocations,
+            frees: self.objects.frees,
+            collections: self.objects.collections,
+            collected: self.objects.collected,
+        }
+    }
+
+    /// Runs a mark-and-sweep collection, as the GC instruction does. Returns the number of
+    /// objects freed
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut marked = BTreeSet::new();
+        let mut pending: Vec<usize> = self
+            .thread_roots()
+            .iter()
+            .filter_map(|&value| self.objects.containing(value))
+            .collect();
+        while let Some(object) = pending.pop() {
+            if !marked.insert(object) {
+                continue;
+            }
+            let size = self.objects.headers[&object];
+            for offset in (0..size.saturating_sub(3)).step_by(4) {
+                let word = &self.heap[object + offset..object + offset + 4];
+                let value = i32::from_be_bytes(word.try_into().unwrap());
+                pending.extend(self.objects.containing(value));
+            }
+        }
+
+        let garbage: Vec<usize> = self
+            .objects
+            .headers
+            .keys()
+            .filter(|object| !marked.contains(object))
+            .copied()
+            .collect();
+        for &object in &garbage {
+            self.free_object(object);
+        }
+        self.objects.collections += 1;
+        self.objects.collected += garbage.len() as u64;
+        garbage.len()
+    }
+
+    // `NEW $size $dst`. Objects are at least 1 byte, so addresses are unique
+    pub(super) fn allocate(&mut self, size: i32, instruction_pc: usize) -> Option<usize> {
+        let size = match usize::try_from(size) {
+            Ok(size) => size.max(1),
+            Err(_) => {
+                self.stop(VmFault::InvalidAllocation {
+                    pc: instruction_pc,
+                    size,
+                });
+                return None;
+            }
+        };
+        if let Some(threshold) = self.config.gc_threshold {
+            if self.objects.object_bytes + size > threshold {
+                self.collect_garbage();
+            }
+        }
+        let address = self
+            .objects
+            .take_free(size)
+            .unwrap_or(self.heap.len().max(NULL_BYTES));
+        let end = address + size;
+        if self.heap.len() < end {
+            self.heap.resize(end, 0);
+        }
+        self.heap[address..end].fill(0);
+        self.objects.headers.insert(address, size);
+        self.objects.object_bytes += size;
+        self.objects.allocations += 1;
+        Some(address)
+    }
+
+    // `FREE $addr`, only the address NEW returned
+    pub(super) fn free(&mut self, address: i32, instruction_pc: usize) -> bool {
+        match usize::try_from(address) {
+            Ok(object) if self.objects.headers.contains_key(&object) => {
+                self.free_object(object);
+                false
+            }
+            _ => self.stop(VmFault::InvalidFree {
+                pc: instruction_pc,
+                address,
+            }),
+        }
+    }
+
+    fn free_object(&mut self, object: usize) {
+        let size = self.objects.headers.remove(&object).unwrap_or_default();
+        self.objects.object_bytes -= size;
+        self.objects.frees += 1;
+        let (address, size) = self.objects.release(object, size);
+        if address + size >= self.heap.len() && address <= self.heap.len() {
+            self.objects.free.remove(&address);
+            self.heap.truncate(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::tests::assembled_vm;
+    use crate::vm::{fault::VmFault, VmConfig};
+
+    #[test]
+    fn test_new_free_reuses_memory() {
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #8
+            new $0 $1
+            new $0 $2
+            load $3 #255
+            stb $3 $1
+            free $1
+            load $0 #4
+            new $0 $1
+            new $0 $4
+            free $2
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        // The 8 bytes of the first object hold both 4 bytes objects, zeroed
+        assert_eq!((vm.register(1), vm.register(4)), (4, 8));
+        assert_eq!(vm.heap(), &[0; 12]);
+        let stats = vm.heap_stats();
+        assert_eq!(
+            (stats.objects, stats.object_bytes, stats.free_bytes),
+            (2, 8, 0)
+        );
+        assert_eq!((stats.allocations, stats.frees), (4, 2));
+
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #4\nnew $0 $1\ninc $1\nfree $1",
+        );
+        vm.run();
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::InvalidFree { pc: 9, address: 5 })
+        );
+        let mut vm = assembled_vm(VmConfig::default(), "load $0 #0\ndec $0\nfree $0");
+        vm.run();
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::InvalidFree { pc: 6, address: -1 })
+        );
+    }
+
+    #[test]
+    fn test_collect_unreachable_objects() {
+        // $1 -> a -> b, c is unreachable, d only from the worker thread
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #8
+            new $0 $1
+            new $0 $2
+            stw $2 $1
+            new $0 $2
+            load $2 #0
+            new $0 $3
+            spawn $5 @worker
+            load $3 #0
+            gc
+            hlt
+            worker: yield
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        let stats = vm.heap_stats();
+        assert_eq!(
+            (stats.objects, stats.collections, stats.collected),
+            (3, 1, 1)
+        );
+        assert_eq!(stats.free_bytes, 8);
+
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #16
+            new $0 $1
+            new $0 $2
+            stw $2 $1
+            load $0 #0
+            load $2 #0
+            gc
+            load $1 #0
+            gc
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.heap_stats().collected, 2);
+        assert_eq!(vm.heap().len(), 4);
+
+        // Only a saved thread's stack holds the object
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "spawn $5 @worker
+            yield
+            gc
+            load $0 #0
+            join $5
+            hlt
+            worker: load $0 #8
+            new $0 $1
+            push $1
+            load $0 #0
+            load $1 #0
+            yield
+            gc
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        let stats = vm.heap_stats();
+        assert_eq!((stats.collections, stats.collected), (2, 0));
+    }
+
+    #[test]
+    fn test_gc_threshold_bounds_the_heap() {
+        // Allocates 1000 objects of 100 bytes, only the last one is reachable
+        let source = "
+            load $0 #100
+            load $1 #0
+            load $2 #1000
+            loop: new $0 $3
+            inc $1
+            jlt $1 $2 @loop
+            hlt
+        ";
+        let mut leaking = assembled_vm(VmConfig::default(), source);
+        leaking.run();
+        assert_eq!(leaking.heap().len(), 4 + 100_000);
+
+        let config = VmConfig {
+            gc_threshold: Some(1000),
+            ..VmConfig::default()
+        };
+        let mut collected = assembled_vm(config, source);
+        collected.run();
+        assert_eq!(collected.fault(), None);
+        assert!(collected.heap().len() <= 4 + 1000);
+        let stats = collected.heap_stats();
+        assert!(stats.objects <= 10 && stats.collections >= 100, "{}", stats);
+    }
+}
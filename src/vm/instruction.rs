@@ -1,12 +1,15 @@
+use super::VM;
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 // 8bits opcode
+#[allow(clippy::upper_case_acronyms)]
 pub enum Opcode {
     LOAD, // `LOAD $0 #500` Load value into register $0. #500 16 bits
     //
-    ADD, // `ADD $0 $1 $2` add $0 + $1 registers. Save to $2 register
-    SUB, // `SUB $0 $1 $2` sub $0 - $1 registers. Save to $2 register
-    MUL, // `MUL $0 $1 $2` mul $0 * $1 registers. Save to $2 register
-    DIV, // `DIV $0 $1 $2` mul $0 / $1 registers. Save to $2 register. Use `remainder`
+    ADD, // `ADD $0 $1 $2` add $0 + $1 registers. Save to $2 register. Wraps, sets flags
+    SUB, // `SUB $0 $1 $2` sub $0 - $1 registers. Save to $2 register. Wraps, sets flags
+    MUL, // `MUL $0 $1 $2` mul $0 * $1 registers. Save to $2 register. Wraps, sets flags
+    DIV, // `DIV $0 $1 $2` mul $0 / $1 registers. Save to $2 register. Save $0 % $1 to `remainder`. Faults if $1 is 0
     //
     HLT, // Stop execution
     //
@@ -14,19 +17,100 @@ pub enum Opcode {
     JMPF, // `JMPF $0`  Jump forwards by $0.      Relative jump // TODO: Should it be 18bits ?
     JMPB, // `JMPB $0`  Jump backwards by $0.     Relative jump // TODO: Should it be 18bits ?
     //
-    EQ,  // `EQ  $0 $1 $unused`  aka ($0 == $1) Save result to `equal_flag`. Equal
-    NEQ, // `NEQ $0 $1 $unused`  aka ($0 != $1) Save result to `equal_flag`. Not equal
-    GT,  // `GT  $0 $1 $unused`  aka ($0 >  $1) Save result to `equal_flag`. Greater than
-    LT,  // `LT  $0 $1 $unused`  aka ($0 <  $1) Save result to `equal_flag`. Less than
-    GTQ, // `GTQ $0 $1 $unused`  aka ($0 >= $1) Save result to `equal_flag`. Greater than OR equal to
-    LTQ, // `LTQ $0 $1 $unused`  aka ($0 <= $1) Save result to `equal_flag`. Less than OR equal to
+    EQ,  // `EQ  $0 $1 $unused`  aka ($0 == $1) Save result to `flags.equal`. Equal
+    NEQ, // `NEQ $0 $1 $unused`  aka ($0 != $1) Save result to `flags.equal`. Not equal
+    GT,  // `GT  $0 $1 $unused`  aka ($0 >  $1) Save result to `flags.equal`. Greater than
+    LT,  // `LT  $0 $1 $unused`  aka ($0 <  $1) Save result to `flags.equal`. Less than
+    GTQ, // `GTQ $0 $1 $unused`  aka ($0 >= $1) Save result to `flags.equal`. Greater than OR equal to
+    LTQ, // `LTQ $0 $1 $unused`  aka ($0 <= $1) Save result to `flags.equal`. Less than OR equal to
     //
-    JEQ, // `JEQ $0` Jump to $0 if equal (`equal_flag` is true)  Absolute jump // TODO: Should it be 18bits ?
-    // TODO: JNEQ ???
+    JEQ, // `JEQ $0` Jump to $0 if equal (`flags.equal` is true)  Absolute jump // TODO: Should it be 18bits ?
     ALOC, // `ALOC $0` Allocate $0 bytes of memory in the heap
     //
-    // TODO: INC `INC $0` Inc $0 register value by 1
-    // TODO: DEC `DEC $0` Dec $0 register value by 1
+    //
+    JNEQ,  // `JNEQ $0`  Jump to $0 if not equal (`flags.equal` is false)  Absolute jump
+    JEQF,  // `JEQF $0`  Jump forwards by $0 if `flags.equal` is true.       Relative jump
+    JEQB,  // `JEQB $0`  Jump backwards by $0 if `flags.equal` is true.      Relative jump
+    JNEQF, // `JNEQF $0` Jump forwards by $0 if `flags.equal` is false.      Relative jump
+    JNEQB, // `JNEQB $0` Jump backwards by $0 if `flags.equal` is false.     Relative jump
+    //
+    JGT, // `JGT  $0 $1 @label` Jump to @label if ($0 >  $1). Target 16 bits. `flags.equal` is untouched
+    JLT, // `JLT  $0 $1 @label` Jump to @label if ($0 <  $1). Target 16 bits. `flags.equal` is untouched
+    JGTQ, // `JGTQ $0 $1 @label` Jump to @label if ($0 >= $1). Target 16 bits. `flags.equal` is untouched
+    JLTQ, // `JLTQ $0 $1 @label` Jump to @label if ($0 <= $1). Target 16 bits. `flags.equal` is untouched
+    //
+    AND, // `AND $0 $1 $2` $0 & $1. Save to $2 register
+    OR,  // `OR  $0 $1 $2` $0 | $1. Save to $2 register
+    XOR, // `XOR $0 $1 $2` $0 ^ $1. Save to $2 register
+    NOT, // `NOT $0 $1`    !$0. Save to $1 register
+    SHL, // `SHL $0 $1 $2` $0 << $1. Save to $2 register. Shift amount is taken modulo 32
+    SHR, // `SHR $0 $1 $2` $0 >> $1 logical, fills with zeros. Save to $2 register
+    SAR, // `SAR $0 $1 $2` $0 >> $1 arithmetic, keeps the sign. Save to $2 register
+    //
+    ANDI, // `ANDI $0 #255 $2` $0 & #255. Save to $2 register. #255 16 bits, zero-extended
+    ORI,  // `ORI  $0 #255 $2` $0 | #255. Save to $2 register. #255 16 bits, zero-extended
+    XORI, // `XORI $0 #255 $2` $0 ^ #255. Save to $2 register. #255 16 bits, zero-extended
+    SHLI, // `SHLI $0 #4 $2`   $0 << #4.  Save to $2 register
+    SHRI, // `SHRI $0 #4 $2`   $0 >> #4 logical.    Save to $2 register
+    SARI, // `SARI $0 #4 $2`   $0 >> #4 arithmetic. Save to $2 register
+    //
+    INC,  // `INC $0` Inc $0 register value by 1
+    DEC,  // `DEC $0` Dec $0 register value by 1
+    MOV,  // `MOV $0 $1` Copy $0 register value to $1 register
+    ADDI, // `ADDI $0 #-5 $2` $0 + #-5. Save to $2 register. #-5 16 bits, sign-extended
+    //
+    MOD, // `MOD $0 $1 $2` $0 % $1. Save to $2 register. Sign follows $0: -7 % 2 = -1
+    MVR, // `MVR $0` Move `remainder` of the last DIV to $0 register
+    //
+    ADDT, // `ADDT $0 $1 $2` Like ADD, but faults with IntegerOverflow instead of wrapping
+    SUBT, // `SUBT $0 $1 $2` Like SUB, but faults with IntegerOverflow instead of wrapping
+    MULT, // `MULT $0 $1 $2` Like MUL, but faults with IntegerOverflow instead of wrapping
+    //
+    JZ,  // `JZ  $0` Jump to $0 if `flags.zero` is set.      Absolute jump
+    JNZ, // `JNZ $0` Jump to $0 if `flags.zero` is clear.    Absolute jump
+    JN,  // `JN  $0` Jump to $0 if `flags.negative` is set.  Absolute jump
+    JC,  // `JC  $0` Jump to $0 if `flags.carry` is set.     Absolute jump
+    JO,  // `JO  $0` Jump to $0 if `flags.overflow` is set.  Absolute jump
+    //
+    // Float instructions address the f64 bank: `$0` is `float_registers[0]`
+    LOADF, // `LOADF $0 #1.5` Load f64 from read-only data into $0. Operand is a 16 bits offset of 8 bytes big-endian
+    ADDF,  // `ADDF $0 $1 $2` $0 + $1. Save to $2 register
+    SUBF,  // `SUBF $0 $1 $2` $0 - $1. Save to $2 register
+    MULF,  // `MULF $0 $1 $2` $0 * $1. Save to $2 register
+    DIVF,  // `DIVF $0 $1 $2` $0 / $1. Save to $2 register. IEEE 754: x / 0.0 is infinity
+    EQF,   // `EQF  $0 $1` aka ($0 == $1) Save result to `flags.equal`
+    NEQF,  // `NEQF $0 $1` aka ($0 != $1) Save result to `flags.equal`
+    GTF,   // `GTF  $0 $1` aka ($0 >  $1) Save result to `flags.equal`
+    LTF,   // `LTF  $0 $1` aka ($0 <  $1) Save result to `flags.equal`
+    GTQF,  // `GTQF $0 $1` aka ($0 >= $1) Save result to `flags.equal`
+    LTQF,  // `LTQF $0 $1` aka ($0 <= $1) Save result to `flags.equal`
+    ITOF,  // `ITOF $0 $1` Integer register $0 to float register $1
+    FTOI,  // `FTOI $0 $1` Float register $0 to integer register $1. Truncates, saturates, NaN is 0
+    //
+    SYSCALL, // `SYSCALL #1` Call host service #1 (16 bits). Arguments and results in $0, $1. See `vm::syscall`
+    LDB,     // `LDB $0 $1` Load heap byte at address $0 into $1 register. Zero-extended
+    STB,     // `STB $0 $1` Store low 8 bits of $0 to heap byte at address $1
+    NATIVE,  // `NATIVE #1` Call host function registered with `VM::register_native(1, ..)`
+    //
+    SPAWN, // `SPAWN $0 @label` Start a green thread at @label with a copy of the registers. Save its id to $0
+    YIELD, // `YIELD` Let the next ready thread run
+    JOIN,  // `JOIN $0` Wait until thread $0 halted
+    //
+    SEND, // `SEND $0 $1` Queue $1 on channel $0. See `vm::channel::Channels`
+    RECV, // `RECV $0 $1` Take the oldest value of channel $0 into $1. Blocks while it is empty
+    //
+    LDW,   // `LDW $0 $1` Load the 32 bits heap word at address $0 into $1. Big-endian
+    STW,   // `STW $0 $1` Store $0 to the 32 bits heap word at address $1. Big-endian
+    CAS, // `CAS $0 $1 $2` If word at $0 == $1 store $2, `flags.equal` = true. Else $1 = word, `flags.equal` = false
+    FADD, // `FADD $0 $1 $2` $2 = word at $0, then the word is incremented by $1. Wraps
+    FENCE, // `FENCE` Memory barrier. Every instruction is sequentially consistent, see `vm::atomic`
+    //
+    NEW, // `NEW $0 $1` Allocate a zeroed heap object of $0 bytes. Save its address to $1. See `vm::gc`
+    FREE, // `FREE $0` Free the heap object at address $0
+    GC,  // `GC` Free heap objects unreachable from registers and stacks
+    //
+    PUSH, // `PUSH $0` Push $0 on the running thread's stack. See `vm::thread`
+    POP,  // `POP $0` Pop the top of the running thread's stack into $0
     //
     IGL, // Illegal
 }
@@ -58,19 +142,275 @@ impl From<u8> for Opcode {
             //
             16 => Opcode::ALOC,
             //
+            17 => Opcode::JNEQ,
+            18 => Opcode::JEQF,
+            19 => Opcode::JEQB,
+            20 => Opcode::JNEQF,
+            21 => Opcode::JNEQB,
+            //
+            22 => Opcode::JGT,
+            23 => Opcode::JLT,
+            24 => Opcode::JGTQ,
+            25 => Opcode::JLTQ,
+            //
+            26 => Opcode::AND,
+            27 => Opcode::OR,
+            28 => Opcode::XOR,
+            29 => Opcode::NOT,
+            30 => Opcode::SHL,
+            31 => Opcode::SHR,
+            32 => Opcode::SAR,
+            //
+            33 => Opcode::ANDI,
+            34 => Opcode::ORI,
+            35 => Opcode::XORI,
+            36 => Opcode::SHLI,
+            37 => Opcode::SHRI,
+            38 => Opcode::SARI,
+            //
+            39 => Opcode::INC,
+            40 => Opcode::DEC,
+            41 => Opcode::MOV,
+            42 => Opcode::ADDI,
+            //
+            43 => Opcode::MOD,
+            44 => Opcode::MVR,
+            //
+            45 => Opcode::ADDT,
+            46 => Opcode::SUBT,
+            47 => Opcode::MULT,
+            //
+            48 => Opcode::JZ,
+            49 => Opcode::JNZ,
+            50 => Opcode::JN,
+            51 => Opcode::JC,
+            52 => Opcode::JO,
+            //
+            53 => Opcode::LOADF,
+            54 => Opcode::ADDF,
+            55 => Opcode::SUBF,
+            56 => Opcode::MULF,
+            57 => Opcode::DIVF,
+            58 => Opcode::EQF,
+            59 => Opcode::NEQF,
+            60 => Opcode::GTF,
+            61 => Opcode::LTF,
+            62 => Opcode::GTQF,
+            63 => Opcode::LTQF,
+            64 => Opcode::ITOF,
+            65 => Opcode::FTOI,
+            //
+            66 => Opcode::SYSCALL,
+            67 => Opcode::LDB,
+            68 => Opcode::STB,
+            69 => Opcode::NATIVE,
+            //
+            70 => Opcode::SPAWN,
+            71 => Opcode::YIELD,
+            72 => Opcode::JOIN,
+            //
+            73 => Opcode::SEND,
+            74 => Opcode::RECV,
+            //
+            75 => Opcode::LDW,
+            76 => Opcode::STW,
+            77 => Opcode::CAS,
+            78 => Opcode::FADD,
+            79 => Opcode::FENCE,
+            //
+            80 => Opcode::NEW,
+            81 => Opcode::FREE,
+            82 => Opcode::GC,
+            //
+            83 => Opcode::PUSH,
+            84 => Opcode::POP,
+            //
             _ => Opcode::IGL,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// Encoded operand of an instruction
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OperandKind {
+    Register,      // 8 bits index into `registers`
+    FloatRegister, // 8 bits index into `float_registers`
+    Immediate,     // 16 bits big-endian number or read-only data offset
+    Target,        // 16 bits big-endian absolute code offset
+}
+
+impl OperandKind {
+    pub fn byte_len(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister => 1,
+            OperandKind::Immediate | OperandKind::Target => 2,
+        }
+    }
+
+    // Size of the register bank the operand indexes, 0 for 16 bits operands
+    pub fn register_count(self) -> usize {
+        match self {
+            OperandKind::Register => VM::REGISTERS,
+            OperandKind::FloatRegister => VM::FLOAT_REGISTERS,
+            OperandKind::Immediate | OperandKind::Target => 0,
+        }
+    }
+}
+
+impl Opcode {
+    // Operands in encoding order. Must match what `VM` executes
+    pub fn operands(self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::LOAD => &[Register, Immediate],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::MOD
+            | Opcode::ADDT
+            | Opcode::SUBT
+            | Opcode::MULT
+            | Opcode::CAS
+            | Opcode::FADD => &[Register, Register, Register],
+            Opcode::HLT | Opcode::YIELD | Opcode::FENCE | Opcode::GC | Opcode::IGL => &[],
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::ALOC
+            | Opcode::JNEQ
+            | Opcode::JEQF
+            | Opcode::JEQB
+            | Opcode::JNEQF
+            | Opcode::JNEQB
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::MVR
+            | Opcode::JZ
+            | Opcode::JNZ
+            | Opcode::JN
+            | Opcode::JC
+            | Opcode::JO
+            | Opcode::JOIN
+            | Opcode::FREE
+            | Opcode::PUSH
+            | Opcode::POP => &[Register],
+            Opcode::JGT | Opcode::JLT | Opcode::JGTQ | Opcode::JLTQ => {
+                &[Register, Register, Target]
+            }
+            Opcode::NOT
+            | Opcode::MOV
+            | Opcode::LDB
+            | Opcode::STB
+            | Opcode::SEND
+            | Opcode::RECV
+            | Opcode::LDW
+            | Opcode::STW
+            | Opcode::NEW => &[Register, Register],
+            Opcode::ANDI
+            | Opcode::ORI
+            | Opcode::XORI
+            | Opcode::SHLI
+            | Opcode::SHRI
+            | Opcode::SARI
+            | Opcode::ADDI => &[Register, Immediate, Register],
+            Opcode::LOADF => &[FloatRegister, Immediate],
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTQF
+            | Opcode::LTQF => &[FloatRegister, FloatRegister],
+            Opcode::ITOF => &[Register, FloatRegister],
+            Opcode::FTOI => &[FloatRegister, Register],
+            Opcode::SYSCALL | Opcode::NATIVE => &[Immediate],
+            Opcode::SPAWN => &[Register, Target],
+        }
+    }
+
+    // Encoded size in bytes, opcode included
+    pub fn byte_len(self) -> usize {
+        1 + self
+            .operands()
+            .iter()
+            .map(|kind| kind.byte_len())
+            .sum::<usize>()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecodeError {
+    Truncated,           // At the end of the program, or operands missing
+    InvalidRegister(u8), // Integer or float register operand past its register bank
+}
+
+// Instruction decoded from bytecode. Register operands (integer or float) are kept
+// in encoding order, the 16 bits operand (immediate or target) in `immediate`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Instruction {
-    opcode: Opcode,
+    pub opcode: Opcode,
+    pub registers: [u8; 3],
+    pub immediate: u16,
+    len: u8,
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            registers: [0; 3],
+            immediate: 0,
+            len: opcode.byte_len() as u8,
+        }
+    }
+
+    // Instruction at `offset`. Fails at the end of `program`, when operands are missing
+    // or name a register past `VM::REGISTERS` (`VM::FLOAT_REGISTERS` for float operands)
+    pub fn decode(program: &[u8], offset: usize) -> Result<Instruction, DecodeError> {
+        let opcode = program.get(offset).ok_or(DecodeError::Truncated)?;
+        let mut instruction = Instruction::new(Opcode::from(*opcode));
+        let operands = program
+            .get(offset + 1..offset + instruction.byte_len())
+            .ok_or(DecodeError::Truncated)?;
+        let (mut position, mut register) = (0, 0);
+        for kind in instruction.opcode.operands() {
+            match kind {
+                OperandKind::Register | OperandKind::FloatRegister => {
+                    let index = operands[position];
+                    if index as usize >= kind.register_count() {
+                        return Err(DecodeError::InvalidRegister(index));
+                    }
+                    instruction.registers[register] = index;
+                    register += 1;
+                }
+                OperandKind::Immediate | OperandKind::Target => {
+                    instruction.immediate =
+                        u16::from_be_bytes([operands[position], operands[position + 1]]);
+                }
+            }
+            position += kind.byte_len();
+        }
+        Ok(instruction)
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.len as usize
     }
 }
 
@@ -84,9 +424,56 @@ mod tests {
         assert_eq!(opcode, Opcode::HLT);
     }
 
+    #[test]
+    fn test_opcode_from_u8_matches_discriminant() {
+        for byte in 0..Opcode::IGL as u8 {
+            assert_eq!(Opcode::from(byte) as u8, byte);
+        }
+        assert_eq!(Opcode::from(Opcode::IGL as u8), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_opcode_operands() {
+        assert_eq!(
+            Opcode::LOAD.operands(),
+            &[OperandKind::Register, OperandKind::Immediate]
+        );
+        assert_eq!(Opcode::JGT.byte_len(), 5);
+        assert_eq!(Opcode::HLT.byte_len(), 1);
+        assert_eq!(Opcode::FTOI.operands()[0], OperandKind::FloatRegister);
+    }
+
     #[test]
     fn test_create_instruction() {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_decode_instruction() {
+        let program = [33, 1, 0, 255, 2, 22, 0, 1];
+        let instruction = Instruction::decode(&program, 0).unwrap();
+        assert_eq!(instruction.opcode, Opcode::ANDI);
+        assert_eq!(instruction.registers, [1, 2, 0]);
+        assert_eq!(instruction.immediate, 255);
+        assert_eq!(instruction.byte_len(), 5);
+        // JGT without its target
+        assert_eq!(
+            Instruction::decode(&program, 5),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            Instruction::decode(&program, 8),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            Instruction::decode(&[0, 6, 0, 1], 0),
+            Err(DecodeError::InvalidRegister(6))
+        );
+        // LOADF $7 #0
+        assert_eq!(
+            Instruction::decode(&[53, 7, 0, 0], 0),
+            Err(DecodeError::InvalidRegister(7))
+        );
+    }
 }
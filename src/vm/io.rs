@@ -0,0 +1,60 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+// Host side of SYSCALL. Swap it with `VM::set_io` to capture or script guest I/O
+pub trait HostIo {
+    fn write(&mut self, text: &str);
+    // Line without the trailing newline, `None` at end of input
+    fn read_line(&mut self) -> Option<String>;
+}
+
+// Process stdin/stdout. Default for a new VM
+pub struct StdIo;
+
+impl HostIo for StdIo {
+    fn write(&mut self, text: &str) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+        }
+    }
+}
+
+// In-memory I/O. Clones share the same buffers, so a test keeps one clone to inspect the output
+#[derive(Clone, Default)]
+pub struct BufferedIo {
+    input: Rc<RefCell<VecDeque<String>>>,
+    output: Rc<RefCell<String>>,
+}
+
+impl BufferedIo {
+    pub fn new(input: &str) -> BufferedIo {
+        BufferedIo {
+            input: Rc::new(RefCell::new(input.lines().map(String::from).collect())),
+            output: Rc::default(),
+        }
+    }
+
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+}
+
+impl HostIo for BufferedIo {
+    fn write(&mut self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        self.input.borrow_mut().pop_front()
+    }
+}
@@ -1,209 +1,801 @@
-use instruction::Opcode;
+use std::collections::HashMap;
+
+use channel::Channels;
+use fault::VmFault;
+use flags::Flags;
+use fusion::Decoded;
+use gc::Objects;
+use instruction::{DecodeError, Instruction, Opcode};
+use io::{HostIo, StdIo};
 use log::{debug, error, info};
+use thread::Thread;
+use verifier::VerifyError;
+
+use crate::cmelf::{Cmelf, CmelfError};
 
+mod atomic;
+pub mod channel;
+pub mod fault;
+pub mod flags;
+mod fusion;
+pub mod gc;
 pub mod instruction;
+pub mod io;
+pub mod syscall;
+mod thread;
+pub mod verifier;
+
+/// Host function called by `NATIVE #id`. Reads arguments from and writes results to registers/heap
+pub type NativeFunction = fn(&mut VM) -> Result<(), VmFault>;
 
+/// Options of a [`VM`]. `Default` matches [`VM::new`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct VmConfig {
+    /// Predecode on load and fuse common instruction pairs (LOAD + ADD, EQ + JEQ, ...)
+    /// into superinstructions dispatched once by `run`
+    pub superinstructions: bool,
+    /// Switch green threads after this many instructions. `None` switches only at
+    /// YIELD, JOIN and HLT
+    pub thread_time_slice: Option<u64>,
+    /// Switch green threads after a pseudo-random 1..=`thread_time_slice` (default 4)
+    /// instructions drawn from this seed. The same seed replays the same interleaving
+    pub interleave_seed: Option<u64>,
+    /// Collect garbage before NEW once heap objects use this many bytes
+    pub gc_threshold: Option<usize>,
+}
+
+/// Why [`VM::run_for`] returned
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RunState {
+    /// Fuel is used up, the program can continue
+    OutOfFuel,
+    /// HLT, EXIT, a fault or the end of the program. See [`VM::fault`] and [`VM::exit_code`]
+    Stopped,
+    /// Waiting in RECV on an empty channel, run again once a message was sent
+    Blocked,
+}
+
+/// Register machine executing CMELF images or raw bytecode.
+///
+/// State is read through accessors. Guest code changes it, the host only through
+/// [`VM::set_register`], [`VM::heap_mut`] and friends, ex. from a [`NativeFunction`].
 pub struct VM {
-    pub registers: [i32; VM::REGISTERS], // 32 = 8bits opcode + 8 register number + 2*8 bits
-    pc: usize,                           // program counter that tracks which byte is being executed
-    pub program: Vec<u8>,                // Bytecode of the program. 8bits for opcode
-    remainder: u32,                      // Modulo for Opcode::DIV
-    pub equal_flag: bool,                // Result of the last comparison operation
-    heap: Vec<u8>,                       // Heap memory in bytes
+    registers: [i32; VM::REGISTERS], // 32 = 8bits opcode + 8 register number + 2*8 bits
+    float_registers: [f64; VM::FLOAT_REGISTERS], // Separate f64 bank for float instructions
+    pc: usize,                       // program counter that tracks which byte is being executed
+    program: Vec<u8>,                // Bytecode of the program. 8bits for opcode
+    remainder: i32,                  // Signed remainder of the last Opcode::DIV
+    flags: Flags,                    // Comparison and arithmetic status
+    heap: Vec<u8>,                   // Heap memory in bytes
+    objects: Objects,                // Objects allocated by NEW in `heap`
+    ro_data: Vec<u8>,                // Read-only data section loaded from CMELF
+    fault: Option<VmFault>,          // Why the last run stopped before HLT
+    exit_code: Option<i32>,          // Set by the EXIT syscall
+    io: Box<dyn HostIo>,             // Host side of SYSCALL
+    natives: HashMap<u16, NativeFunction>, // Host functions by NATIVE id
+    decoded: Vec<Option<Decoded>>,   // Filled by `predecode`, indexed by instruction offset
+    executed: u64,                   // Instructions executed since the program was loaded
+    config: VmConfig,
+    threads: Vec<Thread>, // Green threads by id, empty until the first SPAWN
+    thread: usize,        // Id of the running thread
+    preempt_at: u64,      // `executed` count switching to the next thread
+    channels: Option<Channels>, // Shared with other VMs, used by SEND/RECV
+    blocked: bool,        // The last run stopped in RECV on an empty channel
+    interleave: u64,      // Random state of `VmConfig::interleave_seed`
+    stack: Vec<i32>,      // Stack of the running thread, PUSH/POP
 }
 
 impl VM {
     pub const REGISTERS: usize = 6; // 32
+    pub const FLOAT_REGISTERS: usize = 6;
+    pub const STACK_SIZE: usize = 1024; // Values per thread stack
     pub const SIZE: usize = std::mem::size_of::<u8>() * 8;
     // TODO: u8 const
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
+        VM::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> VM {
         VM {
             registers: [0; VM::REGISTERS],
+            float_registers: [0.0; VM::FLOAT_REGISTERS],
             program: vec![],
             pc: 0,
             remainder: 0,
-            equal_flag: false,
+            flags: Flags::default(),
             heap: vec![],
+            objects: Objects::default(),
+            ro_data: vec![],
+            fault: None,
+            exit_code: None,
+            io: Box::new(StdIo),
+            natives: HashMap::new(),
+            decoded: vec![],
+            executed: 0,
+            config,
+            threads: vec![],
+            thread: 0,
+            preempt_at: u64::MAX,
+            channels: None,
+            blocked: false,
+            interleave: 0,
+            stack: vec![],
         }
     }
 
-    // 8bits -> opcode
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Replaces program and read-only data with the CMELF image contents
+    pub fn load_cmelf(&mut self, bytes: &[u8]) -> Result<(), CmelfError> {
+        let cmelf = Cmelf::from_bytes(bytes)?;
+        self.load_program(cmelf.code);
+        self.ro_data = cmelf.ro_data;
+        Ok(())
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    /// Replaces the program with raw bytecode and rewinds to its start. Read-only data is kept
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.decoded.clear();
+        self.executed = 0;
+        self.threads.clear();
+        self.thread = 0;
+        self.preempt_at = u64::MAX;
+        self.blocked = false;
+        self.interleave = thread::interleave_state(self.config.interleave_seed);
+        self.stack.clear();
+        self.pc = 0;
+        self.fault = None;
+        self.exit_code = None;
+        if self.config.superinstructions {
+            self.predecode();
+        }
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        // program[i] = 11010000
-        // program[i+1] = 11110011
-        // result should be 1101000011110011 (16bits)
-        let result =
-            ((self.program[self.pc] as u16) << VM::SIZE) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    /// Like [`VM::load_program`], but only loads bytecode that passes [`verifier::verify`]
+    pub fn load_verified_program(&mut self, program: Vec<u8>) -> Result<(), Vec<VerifyError>> {
+        verifier::verify(&program)?;
+        self.load_program(program);
+        Ok(())
+    }
+
+    /// Appends one byte of bytecode, ex. an instruction typed into the REPL
+    pub fn add_byte(&mut self, byte: u8) {
+        self.decoded.clear();
+        self.program.push(byte)
+    }
+
+    /// Host I/O used by SYSCALL, [`StdIo`] by default
+    pub fn set_io(&mut self, io: Box<dyn HostIo>) {
+        self.io = io;
+    }
+
+    /// Exposes `function` to guest code as `NATIVE #id`. Returns the function previously registered with `id`
+    pub fn register_native(&mut self, id: u16, function: NativeFunction) -> Option<NativeFunction> {
+        self.natives.insert(id, function)
+    }
+
+    pub fn register(&self, index: usize) -> i32 {
+        self.registers[index]
+    }
+
+    pub fn set_register(&mut self, index: usize, value: i32) {
+        self.registers[index] = value;
+    }
+
+    pub fn registers(&self) -> &[i32; VM::REGISTERS] {
+        &self.registers
+    }
+
+    pub fn float_register(&self, index: usize) -> f64 {
+        self.float_registers[index]
+    }
+
+    pub fn set_float_register(&mut self, index: usize, value: f64) {
+        self.float_registers[index] = value;
+    }
+
+    pub fn float_registers(&self) -> &[f64; VM::FLOAT_REGISTERS] {
+        &self.float_registers
+    }
+
+    /// Signed remainder of the last DIV
+    pub fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// Byte offset of the next instruction
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Stack of the running thread, top last
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    /// Heap is resizable, ex. to return a buffer to the guest
+    pub fn heap_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.heap
+    }
+
+    /// Code passed to the EXIT syscall, `None` if the program did not call it
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Instructions executed since the program was loaded
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Fault which stopped the VM, `None` if it halted normally
+    pub fn fault(&self) -> Option<&VmFault> {
+        self.fault.as_ref()
+    }
+
+    /// Decodes the whole program once, `run` then dispatches over the decoded instructions
+    /// instead of re-reading bytes. Dropped by `add_byte`, redone by `load_program` only
+    /// with [`VmConfig::superinstructions`]
+    pub fn predecode(&mut self) {
+        self.decoded = vec![None; self.program.len()];
+        let mut offset = 0;
+        while let Ok(instruction) = Instruction::decode(&self.program, offset) {
+            self.decoded[offset] = Some(Decoded::Single(instruction));
+            offset += instruction.byte_len();
+        }
+        if self.config.superinstructions {
+            self.fuse_superinstructions();
+        }
+    }
+
+    pub fn is_predecoded(&self) -> bool {
+        !self.decoded.is_empty()
     }
 
     fn execute_instruction(&mut self) -> bool /* Is done */ {
         debug!("pc: {}", self.pc);
         if self.pc >= self.program.len() {
-            return true;
+            return self.finish_thread(self.pc);
+        }
+        let instruction_pc = self.pc;
+        let instruction = match Instruction::decode(&self.program, instruction_pc) {
+            Ok(instruction) => instruction,
+            Err(DecodeError::Truncated) => {
+                return self.stop(VmFault::TruncatedInstruction { pc: instruction_pc })
+            }
+            Err(DecodeError::InvalidRegister(register)) => {
+                return self.stop(VmFault::InvalidRegister {
+                    pc: instruction_pc,
+                    register,
+                })
+            }
+        };
+        debug!("Opcode: {:?}", instruction.opcode);
+        self.execute_decoded(instruction, instruction_pc)
+    }
+
+    // Jumps into the middle of an instruction fall back to decoding bytes.
+    // Without `fuse` a superinstruction runs only its first instruction
+    fn execute_predecoded(&mut self, fuse: bool) -> bool /* Is done */ {
+        match self.decoded.get(self.pc) {
+            Some(&Some(Decoded::Fused {
+                superinstruction,
+                len,
+                ..
+            })) if fuse => self.execute_fused(superinstruction, len),
+            Some(&Some(decoded)) => self.execute_decoded(decoded.first(), self.pc),
+            _ => self.execute_instruction(),
         }
-        let decoded_opcode = self.decode_opcode();
-        debug!("Opcode: {:?}", decoded_opcode);
-        match decoded_opcode {
+    }
+
+    fn execute_decoded(&mut self, instruction: Instruction, instruction_pc: usize) -> bool {
+        self.pc = instruction_pc + instruction.byte_len();
+        self.executed += 1;
+        let [a, b, c] = instruction.registers.map(usize::from);
+        let immediate = instruction.immediate;
+        match instruction.opcode {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u32;
-                self.registers[register] = number as i32;
+                self.registers[a] = immediate as i32;
                 false
             }
             //
             Opcode::ADD => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = value1 + value2;
+                self.registers[c] = self.add_with_flags(self.registers[a], self.registers[b]);
                 false
             }
             Opcode::SUB => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = value1 - value2;
+                self.registers[c] = self.sub_with_flags(self.registers[a], self.registers[b]);
                 false
             }
             Opcode::MUL => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = value1 * value2;
+                self.registers[c] = self.mul_with_flags(self.registers[a], self.registers[b]);
                 false
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32; // TODO: u32 ????
+                let (value1, value2) = (self.registers[a], self.registers[b]);
+                if value2 == 0 {
+                    return self.stop(VmFault::DivisionByZero { pc: instruction_pc });
+                }
+                self.registers[c] = value1.wrapping_div(value2);
+                self.remainder = value1.wrapping_rem(value2);
                 false
             }
             //
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
-                // TODO: `Eat 8+8bits ??`
+            Opcode::ADDT => {
+                let result = self.add_with_flags(self.registers[a], self.registers[b]);
+                self.store_checked(result, c, instruction_pc)
+            }
+            Opcode::SUBT => {
+                let result = self.sub_with_flags(self.registers[a], self.registers[b]);
+                self.store_checked(result, c, instruction_pc)
+            }
+            Opcode::MULT => {
+                let result = self.mul_with_flags(self.registers[a], self.registers[b]);
+                self.store_checked(result, c, instruction_pc)
+            }
+            //
+            Opcode::JMP => self.jump_if(true, self.registers[a]),
+            Opcode::JMPF => self.jump_relative(true, true, self.registers[a], instruction_pc),
+            Opcode::JMPB => self.jump_relative(true, false, self.registers[a], instruction_pc),
+            //
+            Opcode::EQ => self.compare(self.registers[a] == self.registers[b]),
+            Opcode::NEQ => self.compare(self.registers[a] != self.registers[b]),
+            Opcode::GT => self.compare(self.registers[a] > self.registers[b]),
+            Opcode::LT => self.compare(self.registers[a] < self.registers[b]),
+            Opcode::GTQ => self.compare(self.registers[a] >= self.registers[b]),
+            Opcode::LTQ => self.compare(self.registers[a] <= self.registers[b]),
+            //
+            Opcode::JEQ => self.jump_if(self.flags.equal, self.registers[a]),
+            Opcode::JNEQ => self.jump_if(!self.flags.equal, self.registers[a]),
+            Opcode::JEQF => {
+                self.jump_relative(self.flags.equal, true, self.registers[a], instruction_pc)
+            }
+            Opcode::JEQB => {
+                self.jump_relative(self.flags.equal, false, self.registers[a], instruction_pc)
+            }
+            Opcode::JNEQF => {
+                self.jump_relative(!self.flags.equal, true, self.registers[a], instruction_pc)
+            }
+            Opcode::JNEQB => {
+                self.jump_relative(!self.flags.equal, false, self.registers[a], instruction_pc)
+            }
+            //
+            Opcode::JGT => self.jump_if(self.registers[a] > self.registers[b], immediate as i32),
+            Opcode::JLT => self.jump_if(self.registers[a] < self.registers[b], immediate as i32),
+            Opcode::JGTQ => self.jump_if(self.registers[a] >= self.registers[b], immediate as i32),
+            Opcode::JLTQ => self.jump_if(self.registers[a] <= self.registers[b], immediate as i32),
+            //
+            Opcode::AND => {
+                self.registers[c] = self.registers[a] & self.registers[b];
+                false
+            }
+            Opcode::OR => {
+                self.registers[c] = self.registers[a] | self.registers[b];
                 false
             }
-            Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
-                // TODO: `Eat 8+8bits ??`
+            Opcode::XOR => {
+                self.registers[c] = self.registers[a] ^ self.registers[b];
                 false
             }
-            Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
-                // TODO: `Eat 8+8bits ??`
+            Opcode::NOT => {
+                self.registers[b] = !self.registers[a];
+                false
+            }
+            Opcode::SHL => {
+                let shift = self.registers[b] as u32;
+                self.registers[c] = self.registers[a].wrapping_shl(shift);
+                false
+            }
+            Opcode::SHR => {
+                let shift = self.registers[b] as u32;
+                self.registers[c] = (self.registers[a] as u32).wrapping_shr(shift) as i32;
+                false
+            }
+            Opcode::SAR => {
+                let shift = self.registers[b] as u32;
+                self.registers[c] = self.registers[a].wrapping_shr(shift);
+                false
+            }
+            //
+            Opcode::ANDI => {
+                self.registers[b] = self.registers[a] & immediate as i32;
+                false
+            }
+            Opcode::ORI => {
+                self.registers[b] = self.registers[a] | immediate as i32;
+                false
+            }
+            Opcode::XORI => {
+                self.registers[b] = self.registers[a] ^ immediate as i32;
+                false
+            }
+            Opcode::SHLI => {
+                self.registers[b] = self.registers[a].wrapping_shl(immediate as u32);
+                false
+            }
+            Opcode::SHRI => {
+                self.registers[b] =
+                    (self.registers[a] as u32).wrapping_shr(immediate as u32) as i32;
+                false
+            }
+            Opcode::SARI => {
+                self.registers[b] = self.registers[a].wrapping_shr(immediate as u32);
+                false
+            }
+            //
+            Opcode::INC => {
+                self.registers[a] = self.add_with_flags(self.registers[a], 1);
+                false
+            }
+            Opcode::DEC => {
+                self.registers[a] = self.sub_with_flags(self.registers[a], 1);
+                false
+            }
+            Opcode::MOV => {
+                self.registers[b] = self.registers[a];
+                false
+            }
+            Opcode::ADDI => {
+                let immediate = immediate as i16 as i32;
+                self.registers[b] = self.add_with_flags(self.registers[a], immediate);
                 false
             }
             //
-            Opcode::EQ => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 == value2;
-                self.next_8_bits();
+            Opcode::MOD => {
+                let (value1, value2) = (self.registers[a], self.registers[b]);
+                if value2 == 0 {
+                    return self.stop(VmFault::DivisionByZero { pc: instruction_pc });
+                }
+                self.registers[c] = value1.wrapping_rem(value2);
                 false
             }
-            Opcode::NEQ => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 != value2;
-                self.next_8_bits();
+            Opcode::MVR => {
+                self.registers[a] = self.remainder;
                 false
             }
-            Opcode::GT => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 > value2;
-                self.next_8_bits();
+            //
+            Opcode::ALOC => match usize::try_from(self.registers[a]) {
+                Ok(size) => {
+                    self.heap.resize(self.heap.len() + size, 0);
+                    false
+                }
+                Err(_) => self.stop(VmFault::InvalidAllocation {
+                    pc: instruction_pc,
+                    size: self.registers[a],
+                }),
+            },
+            //
+            Opcode::JZ => self.jump_if(self.flags.zero, self.registers[a]),
+            Opcode::JNZ => self.jump_if(!self.flags.zero, self.registers[a]),
+            Opcode::JN => self.jump_if(self.flags.negative, self.registers[a]),
+            Opcode::JC => self.jump_if(self.flags.carry, self.registers[a]),
+            Opcode::JO => self.jump_if(self.flags.overflow, self.registers[a]),
+            //
+            Opcode::LOADF => {
+                let offset = immediate as usize;
+                match self.ro_data.get(offset..offset + 8) {
+                    Some(bytes) => {
+                        let bytes: [u8; 8] = bytes.try_into().unwrap();
+                        self.float_registers[a] = f64::from_be_bytes(bytes);
+                        false
+                    }
+                    None => self.stop(VmFault::InvalidAddress {
+                        pc: instruction_pc,
+                        address: offset,
+                    }),
+                }
+            }
+            Opcode::ADDF => {
+                self.float_registers[c] = self.float_registers[a] + self.float_registers[b];
+                false
+            }
+            Opcode::SUBF => {
+                self.float_registers[c] = self.float_registers[a] - self.float_registers[b];
                 false
             }
-            Opcode::LT => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 < value2;
-                self.next_8_bits();
+            Opcode::MULF => {
+                self.float_registers[c] = self.float_registers[a] * self.float_registers[b];
                 false
             }
-            Opcode::GTQ => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 >= value2;
-                self.next_8_bits();
+            Opcode::DIVF => {
+                self.float_registers[c] = self.float_registers[a] / self.float_registers[b];
                 false
             }
-            Opcode::LTQ => {
-                let value1 = self.registers[self.next_8_bits() as usize];
-                let value2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = value1 <= value2;
-                self.next_8_bits();
+            Opcode::EQF => self.compare(self.float_registers[a] == self.float_registers[b]),
+            Opcode::NEQF => self.compare(self.float_registers[a] != self.float_registers[b]),
+            Opcode::GTF => self.compare(self.float_registers[a] > self.float_registers[b]),
+            Opcode::LTF => self.compare(self.float_registers[a] < self.float_registers[b]),
+            Opcode::GTQF => self.compare(self.float_registers[a] >= self.float_registers[b]),
+            Opcode::LTQF => self.compare(self.float_registers[a] <= self.float_registers[b]),
+            Opcode::ITOF => {
+                self.float_registers[b] = self.registers[a] as f64;
+                false
+            }
+            Opcode::FTOI => {
+                self.registers[b] = self.float_registers[a] as i32;
+                false
+            }
+            //
+            Opcode::SYSCALL => self.execute_syscall(immediate, instruction_pc),
+            Opcode::LDB => {
+                let address = self.registers[a] as usize;
+                match self.heap.get(address) {
+                    Some(&byte) => {
+                        self.registers[b] = byte as i32;
+                        false
+                    }
+                    None => self.stop(VmFault::InvalidAddress {
+                        pc: instruction_pc,
+                        address,
+                    }),
+                }
+            }
+            Opcode::STB => {
+                let value = self.registers[a];
+                let address = self.registers[b] as usize;
+                match self.heap.get_mut(address) {
+                    Some(byte) => {
+                        *byte = value as u8;
+                        false
+                    }
+                    None => self.stop(VmFault::InvalidAddress {
+                        pc: instruction_pc,
+                        address,
+                    }),
+                }
+            }
+            Opcode::NATIVE => {
+                let id = immediate;
+                let function = match self.natives.get(&id) {
+                    Some(&function) => function,
+                    None => {
+                        return self.stop(VmFault::UnknownNative {
+                            pc: instruction_pc,
+                            id,
+                        })
+                    }
+                };
+                match function(self) {
+                    Ok(()) => false,
+                    Err(VmFault::Native { id, message, .. }) => self.stop(VmFault::Native {
+                        pc: instruction_pc,
+                        id,
+                        message,
+                    }),
+                    Err(fault) => self.stop(fault),
+                }
+            }
+            //
+            //
+            Opcode::SPAWN => {
+                self.registers[a] = self.spawn_thread(immediate as usize) as i32;
                 false
             }
+            Opcode::YIELD => self.switch_thread(instruction_pc),
+            Opcode::JOIN => self.join_thread(self.registers[a], instruction_pc),
+            //
+            Opcode::SEND => self.send(self.registers[a], self.registers[b], instruction_pc),
+            Opcode::RECV => self.receive(self.registers[a], b, instruction_pc),
             //
-            Opcode::JEQ => {
-                let value = self.registers[self.next_8_bits() as usize];
-                if self.equal_flag {
-                    self.pc = value as usize;
+            Opcode::LDW => match self.load_word(self.registers[a], instruction_pc) {
+                Some(value) => {
+                    self.registers[b] = value;
+                    false
                 }
-                // TODO: `Eat 8+8bits ??`
+                None => true,
+            },
+            Opcode::STW => self.store_word(self.registers[b], self.registers[a], instruction_pc),
+            Opcode::CAS => self.compare_and_swap([a, b, c], instruction_pc),
+            Opcode::FADD => match self.load_word(self.registers[a], instruction_pc) {
+                Some(value) => {
+                    self.registers[c] = value;
+                    let sum = value.wrapping_add(self.registers[b]);
+                    self.store_word(self.registers[a], sum, instruction_pc)
+                }
+                None => true,
+            },
+            Opcode::FENCE => false,
+            //
+            Opcode::NEW => match self.allocate(self.registers[a], instruction_pc) {
+                Some(address) => {
+                    self.registers[b] = address as i32;
+                    false
+                }
+                None => true,
+            },
+            Opcode::FREE => self.free(self.registers[a], instruction_pc),
+            Opcode::GC => {
+                self.collect_garbage();
                 false
             }
             //
-            Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+            Opcode::PUSH if self.stack.len() < VM::STACK_SIZE => {
+                self.stack.push(self.registers[a]);
                 false
             }
+            Opcode::PUSH => self.stop(VmFault::StackOverflow { pc: instruction_pc }),
+            Opcode::POP => match self.stack.pop() {
+                Some(value) => {
+                    self.registers[a] = value;
+                    false
+                }
+                None => self.stop(VmFault::StackUnderflow { pc: instruction_pc }),
+            },
             //
             Opcode::HLT => {
                 info!("HLT encountered");
-                true
+                self.finish_thread(instruction_pc)
             }
-            _ => {
+            Opcode::IGL => {
                 error!("Unrecognized opcode found! Terminating!");
-                true
+                let opcode = self.program[instruction_pc];
+                self.stop(VmFault::IllegalOpcode {
+                    pc: instruction_pc,
+                    opcode,
+                })
             }
         }
     }
 
-    pub fn add_byte(&mut self, byte: u8) {
-        self.program.push(byte)
+    // Wrapping arithmetic. Result is the same in debug and release builds
+    fn add_with_flags(&mut self, value1: i32, value2: i32) -> i32 {
+        let (result, overflow) = value1.overflowing_add(value2);
+        let (_, carry) = (value1 as u32).overflowing_add(value2 as u32);
+        self.flags.set_arithmetic(result, carry, overflow);
+        result
+    }
+
+    fn sub_with_flags(&mut self, value1: i32, value2: i32) -> i32 {
+        let (result, overflow) = value1.overflowing_sub(value2);
+        let (_, borrow) = (value1 as u32).overflowing_sub(value2 as u32);
+        self.flags.set_arithmetic(result, borrow, overflow);
+        result
+    }
+
+    fn mul_with_flags(&mut self, value1: i32, value2: i32) -> i32 {
+        let (result, overflow) = value1.overflowing_mul(value2);
+        self.flags.set_arithmetic(result, overflow, overflow);
+        result
+    }
+
+    // Destination register of a trapping instruction is left untouched on overflow
+    fn store_checked(&mut self, result: i32, register: usize, instruction_pc: usize) -> bool {
+        if self.flags.overflow {
+            return self.stop(VmFault::IntegerOverflow { pc: instruction_pc });
+        }
+        self.registers[register] = result;
+        false
+    }
+
+    fn compare(&mut self, result: bool) -> bool {
+        self.flags.equal = result;
+        false
+    }
+
+    fn jump_if(&mut self, condition: bool, target: i32) -> bool {
+        if condition {
+            self.pc = target as usize;
+        }
+        false
+    }
+
+    // By `distance` bytes from the next instruction. Faults on a negative distance or
+    // a jump before the start of the program
+    fn jump_relative(
+        &mut self,
+        condition: bool,
+        forward: bool,
+        distance: i32,
+        instruction_pc: usize,
+    ) -> bool {
+        if !condition {
+            return false;
+        }
+        let target = usize::try_from(distance).ok().and_then(|distance| {
+            if forward {
+                self.pc.checked_add(distance)
+            } else {
+                self.pc.checked_sub(distance)
+            }
+        });
+        match target {
+            Some(target) => {
+                self.pc = target;
+                false
+            }
+            None => self.stop(VmFault::InvalidJump {
+                pc: instruction_pc,
+                distance,
+            }),
+        }
+    }
+
+    fn stop(&mut self, fault: VmFault) -> bool {
+        debug!("Fault: {}", fault);
+        self.fault = Some(fault);
+        true
+    }
+
+    // One dispatch of the run loops, switching green threads at the end of a time slice
+    fn step(&mut self, fuse: bool) -> bool /* Is done */ {
+        if self.executed >= self.preempt_at {
+            self.switch_thread(self.pc);
+        }
+        // A superinstruction would run past the preemption point
+        self.execute_predecoded(fuse && self.preempt_at - self.executed > 1)
     }
 
-    // Run 1 instruction: 32bits - 4bytes
+    /// Runs one instruction
     pub fn run_once(&mut self) {
-        self.execute_instruction();
+        self.blocked = false;
+        self.step(false);
     }
 
+    /// Runs until HLT, EXIT, a fault or the end of the program, or until RECV blocks
+    /// (see [`VM::is_blocked`])
     pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+        self.fault = None;
+        self.exit_code = None;
+        self.blocked = false;
+        while !self.step(true) {}
+    }
+
+    /// Runs at most `fuel` instructions, ex. to interleave VMs or bound a runaway program.
+    /// Resumes where the previous call stopped. Fault and exit code are not reset, a stopped
+    /// VM should be reloaded before it runs again
+    pub fn run_for(&mut self, fuel: u64) -> RunState {
+        let limit = self.executed.saturating_add(fuel);
+        self.blocked = false;
+        while self.executed < limit {
+            // A superinstruction would overrun the last unit of fuel
+            if self.step(limit - self.executed > 1) {
+                return if self.blocked {
+                    RunState::Blocked
+                } else {
+                    RunState::Stopped
+                };
+            }
         }
+        RunState::OutOfFuel
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+
+    // VM with `source` assembled and loaded, not run yet. Shared by the tests of the vm modules
+    pub(super) fn assembled_vm(config: VmConfig, source: &str) -> VM {
+        let mut vm = VM::with_config(config);
+        vm.load_cmelf(&Assembler::new().assemble(source).unwrap())
+            .unwrap();
+        vm
+    }
 
     #[test]
     fn test_create_vm() {
@@ -227,6 +819,10 @@ mod tests {
         test_vm.program = test_bytes;
         test_vm.run();
         assert_eq!(test_vm.pc, 1);
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::IllegalOpcode { pc: 0, opcode: 200 })
+        );
     }
 
     #[test]
@@ -265,6 +861,33 @@ mod tests {
         assert_eq!(test_vm.pc, 4);
     }
 
+    #[test]
+    fn test_relative_jump_faults() {
+        // `load $0 #100; eq $0 $0 $0; jeqb $0` jumps before the start
+        let mut test_vm = VM::new();
+        test_vm.load_program(vec![0, 0, 0, 100, 9, 0, 0, 0, 19, 0]);
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::InvalidJump {
+                pc: 8,
+                distance: 100
+            })
+        );
+        for opcode in [Opcode::JMPF, Opcode::JMPB] {
+            test_vm.load_program(vec![opcode as u8, 0]);
+            test_vm.registers[0] = -1;
+            test_vm.run();
+            assert_eq!(
+                test_vm.fault(),
+                Some(&VmFault::InvalidJump {
+                    pc: 0,
+                    distance: -1
+                })
+            );
+        }
+    }
+
     #[test]
     fn test_eq_opcode() {
         let mut test_vm = VM::new();
@@ -272,10 +895,10 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
@@ -285,10 +908,10 @@ mod tests {
         test_vm.registers[1] = 11;
         test_vm.program = vec![10, 0, 1, 0, 10, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 10;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
@@ -298,10 +921,10 @@ mod tests {
         test_vm.registers[1] = 11;
         test_vm.program = vec![11, 0, 1, 0, 11, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
@@ -311,10 +934,10 @@ mod tests {
         test_vm.registers[1] = 19;
         test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 10;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
@@ -324,10 +947,10 @@ mod tests {
         test_vm.registers[1] = 15;
         test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
@@ -337,22 +960,486 @@ mod tests {
         test_vm.registers[1] = 15;
         test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.equal);
         test_vm.registers[1] = 10;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.equal);
     }
 
     #[test]
     fn test_jeq_opcode() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 7;
-        test_vm.equal_flag = true;
+        test_vm.flags.equal = true;
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
         test_vm.run_once();
         assert_eq!(test_vm.pc, 7);
     }
 
+    #[test]
+    fn test_jneq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![17, 0, 17, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 7);
+        test_vm.flags.equal = true;
+        test_vm.pc = 2;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_relative_conditional_jumps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.flags.equal = true;
+        test_vm.program = vec![18, 0, 20, 0, 0, 19, 0, 21, 0];
+        test_vm.run_once(); // JEQF taken
+        assert_eq!(test_vm.pc, 5);
+        test_vm.run_once(); // JEQB taken
+        assert_eq!(test_vm.pc, 4);
+        test_vm.pc = 2;
+        test_vm.run_once(); // JNEQF not taken
+        assert_eq!(test_vm.pc, 4);
+        test_vm.flags.equal = false;
+        test_vm.pc = 7;
+        test_vm.run_once(); // JNEQB taken
+        assert_eq!(test_vm.pc, 6);
+    }
+
+    #[test]
+    fn test_compare_and_branch_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![22, 0, 1, 0, 100, 23, 0, 1, 0, 200];
+        test_vm.run_once(); // JGT not taken
+        assert_eq!(test_vm.pc, 5);
+        test_vm.run_once(); // JLT taken
+        assert_eq!(test_vm.pc, 200);
+        test_vm.program = vec![24, 0, 0, 0, 42, 25, 1, 0, 0, 100];
+        test_vm.pc = 0;
+        test_vm.run_once(); // JGTQ taken on equal values
+        assert_eq!(test_vm.pc, 42);
+        test_vm.pc = 5;
+        test_vm.run_once(); // JLTQ not taken
+        assert_eq!(test_vm.pc, 10);
+        assert!(!test_vm.flags.equal);
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![26, 0, 1, 2, 27, 0, 1, 3, 28, 0, 1, 4, 29, 0, 5];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 0b1000);
+        assert_eq!(test_vm.registers[3], 0b1110);
+        assert_eq!(test_vm.registers[4], 0b0110);
+        assert_eq!(test_vm.registers[5], !0b1100);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![30, 0, 1, 2, 31, 0, 1, 3, 32, 0, 1, 4];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], -64);
+        assert_eq!(test_vm.registers[3], 0x3FFF_FFFC);
+        assert_eq!(test_vm.registers[4], -4);
+    }
+
+    #[test]
+    fn test_immediate_bitwise_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![
+            33, 0, 0xFF, 0x00, 1, // ANDI zero-extends the immediate
+            34, 1, 0x00, 0x0F, 2, //
+            35, 2, 0xFF, 0xFF, 3, //
+            36, 3, 0, 4, 4, //
+            37, 0, 0, 28, 5, //
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers[1], 0xFF00);
+        assert_eq!(test_vm.registers[2], 0xFF0F);
+        assert_eq!(test_vm.registers[3], 0x00F0);
+        assert_eq!(test_vm.registers[4], 0x0F00);
+        assert_eq!(test_vm.registers[5], 0xF);
+        test_vm.program = vec![38, 0, 0, 31, 1];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.registers[1], -1);
+    }
+
+    #[test]
+    fn test_inc_dec_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![39, 0, 39, 0, 40, 1];
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 12);
+        assert_eq!(test_vm.registers[1], -1);
+    }
+
+    #[test]
+    fn test_mov_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        test_vm.program = vec![41, 0, 3];
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 42);
+        assert_eq!(test_vm.registers[3], 42);
+    }
+
+    #[test]
+    fn test_addi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![42, 0, 0x01, 0x00, 1, 42, 0, 0xFF, 0xFB, 2];
+        test_vm.run();
+        assert_eq!(test_vm.registers[1], 266);
+        assert_eq!(test_vm.registers[2], 5); // 0xFFFB is -5
+    }
+
+    #[test]
+    fn test_div_remainder() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![4, 0, 1, 2, 44, 3];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.remainder, -1);
+        assert_eq!(test_vm.registers[3], -1);
+    }
+
+    #[test]
+    fn test_mod_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.registers[1] = -3;
+        test_vm.program = vec![43, 0, 1, 2, 43, 1, 0, 3];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 1);
+        assert_eq!(test_vm.registers[3], -3);
+        assert_eq!(test_vm.remainder, 0);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_and_sets_flags() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![1, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.flags.overflow && test_vm.flags.negative);
+        assert!(!test_vm.flags.carry && !test_vm.flags.zero);
+
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![1, 0, 1, 2];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 0);
+        assert!(test_vm.flags.zero && test_vm.flags.carry);
+        assert!(!test_vm.flags.overflow && !test_vm.flags.negative);
+
+        test_vm.registers[0] = 0;
+        test_vm.program = vec![2, 0, 1, 2, 3, 1, 1, 3];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], -1);
+        assert!(test_vm.flags.carry && test_vm.flags.negative);
+        test_vm.registers[1] = 1 << 16;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 0);
+        assert!(test_vm.flags.overflow && test_vm.flags.carry);
+    }
+
+    #[test]
+    fn test_comparison_keeps_arithmetic_flags() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![40, 0, 9, 0, 0, 0];
+        test_vm.run();
+        assert!(test_vm.flags.negative && test_vm.flags.equal);
+    }
+
+    #[test]
+    fn test_trapping_arithmetic() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 7;
+        test_vm.program = vec![45, 1, 1, 3, 46, 0, 1, 2, 5];
+        test_vm.run();
+        assert_eq!(test_vm.registers[3], 2);
+        assert_eq!(test_vm.registers[2], 7);
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.fault(), Some(&VmFault::IntegerOverflow { pc: 4 }));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut test_vm = VM::with_config(VmConfig {
+            superinstructions: true,
+            ..VmConfig::default()
+        });
+        // LOAD $1 #3, loop: LOAD $2 #1, ADD $0 $2 $0, LT $0 $1, LOAD $3 #4, JEQ $3, HLT
+        test_vm.load_program(vec![
+            0, 1, 0, 3, 0, 2, 0, 1, 1, 0, 2, 0, 12, 0, 1, 0, 0, 3, 0, 4, 15, 3, 5,
+        ]);
+        assert_eq!(test_vm.run_for(0), RunState::OutOfFuel);
+        assert_eq!(test_vm.run_for(2), RunState::OutOfFuel);
+        assert_eq!((test_vm.pc(), test_vm.instructions_executed()), (8, 2));
+        assert_eq!(test_vm.run_for(3), RunState::OutOfFuel); // Not the whole LOAD + JEQ pair
+        assert_eq!((test_vm.pc(), test_vm.instructions_executed()), (20, 5));
+        assert_eq!(test_vm.run_for(100), RunState::Stopped);
+        assert_eq!(test_vm.register(0), 3);
+        assert_eq!(test_vm.instructions_executed(), 17);
+        assert_eq!(test_vm.fault(), None);
+
+        // Unlimited fuel after instructions ran
+        test_vm.load_program(vec![0, 1, 0, 3, 5]);
+        assert_eq!(test_vm.run_for(1), RunState::OutOfFuel);
+        assert_eq!(test_vm.run_for(u64::MAX), RunState::Stopped);
+    }
+
+    #[test]
+    fn test_truncated_instruction_fault() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![39, 0, 0, 1];
+        test_vm.run();
+        assert_eq!(test_vm.register(0), 1);
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::TruncatedInstruction { pc: 2 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register_and_allocation_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 9, 0, 1];
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::InvalidRegister { pc: 0, register: 9 })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![16, 0];
+        test_vm.run();
+        assert!(test_vm.heap().is_empty());
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::InvalidAllocation { pc: 0, size: -1 })
+        );
+    }
+
+    #[test]
+    fn test_predecoded_run_matches_bytes() {
+        // $0 counts to 1000, then JMPF lands inside the last LOAD, which is not predecoded
+        let program = vec![
+            0, 1, 3, 232, // LOAD $1 #1000
+            0, 2, 0, 9, // LOAD $2 #9
+            39, 0, // loop: INC $0
+            23, 0, 1, 0, 8, // JLT $0 $1 @loop
+            0, 3, 0, 1, // LOAD $3 #1
+            7, 3, // JMPF $3
+            0, 5, 0, 0, // Byte 22 decodes as HLT
+        ];
+        let mut bytes_vm = VM::new();
+        bytes_vm.load_program(program.clone());
+        bytes_vm.run();
+        let mut decoded_vm = VM::new();
+        decoded_vm.load_program(program);
+        decoded_vm.predecode();
+        assert!(decoded_vm.is_predecoded());
+        decoded_vm.run();
+        assert_eq!(decoded_vm.register(0), 1000);
+        assert_eq!(decoded_vm.registers(), bytes_vm.registers());
+        assert_eq!(decoded_vm.pc(), bytes_vm.pc());
+        assert_eq!(decoded_vm.pc(), 23);
+        assert_eq!(decoded_vm.instructions_executed(), 2005);
+        assert_eq!(bytes_vm.instructions_executed(), 2005);
+        decoded_vm.add_byte(5);
+        assert!(!decoded_vm.is_predecoded());
+    }
+
+    #[test]
+    fn test_division_by_zero_fault() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.fault(), Some(&VmFault::DivisionByZero { pc: 0 }));
+        test_vm.program = vec![43, 0, 1, 2];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.fault(), Some(&VmFault::DivisionByZero { pc: 0 }));
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.fault(), None);
+        assert_eq!(test_vm.registers[2], i32::MIN);
+    }
+
+    #[test]
+    fn test_flag_jumps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.flags.zero = true;
+        test_vm.flags.carry = true;
+        test_vm.program = vec![48, 0, 49, 0, 50, 0, 51, 0, 52, 0];
+        test_vm.run_once(); // JZ taken
+        assert_eq!(test_vm.pc, 100);
+        for (pc, expected) in [(2, 4), (4, 6), (6, 100), (8, 10)] {
+            test_vm.pc = pc;
+            test_vm.run_once();
+            assert_eq!(test_vm.pc, expected);
+        }
+    }
+
+    #[test]
+    fn test_loadf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![0xFF];
+        test_vm.ro_data.extend_from_slice(&2.5f64.to_be_bytes());
+        test_vm.program = vec![53, 1, 0, 1, 53, 2, 0, 2];
+        test_vm.run();
+        assert_eq!(test_vm.float_registers[1], 2.5);
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::InvalidAddress { pc: 4, address: 2 })
+        );
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![54, 0, 1, 2, 55, 0, 1, 3, 56, 0, 1, 4, 57, 0, 1, 5];
+        test_vm.run();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+        assert_eq!(test_vm.registers, [0; VM::REGISTERS]);
+    }
+
+    #[test]
+    fn test_float_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.0;
+        test_vm.program = vec![58, 0, 1, 59, 0, 1, 60, 0, 1, 61, 0, 1, 62, 0, 0, 63, 1, 0];
+        let expected = [false, true, false, true, true, false];
+        for equal in expected {
+            test_vm.run_once();
+            assert_eq!(test_vm.flags.equal, equal);
+        }
+        test_vm.float_registers[0] = f64::NAN;
+        test_vm.program = vec![58, 0, 0];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert!(!test_vm.flags.equal);
+    }
+
+    #[test]
+    fn test_float_conversion_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[1] = -2.75;
+        test_vm.float_registers[2] = 1e20;
+        test_vm.program = vec![64, 0, 0, 65, 1, 1, 65, 2, 2];
+        test_vm.run();
+        assert_eq!(test_vm.float_registers[0], -7.0);
+        assert_eq!(test_vm.registers[1], -2);
+        assert_eq!(test_vm.registers[2], i32::MAX);
+    }
+
+    #[test]
+    fn test_heap_byte_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x1FF;
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![68, 0, 1, 67, 1, 2, 67, 0, 2];
+        test_vm.run();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0xFF]);
+        assert_eq!(test_vm.registers[2], 0xFF);
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::InvalidAddress {
+                pc: 6,
+                address: 0x1FF
+            })
+        );
+    }
+
+    #[test]
+    fn test_native_opcode() {
+        fn sum_heap(vm: &mut VM) -> Result<(), VmFault> {
+            let sum = vm.heap().iter().map(|&b| b as i32).sum();
+            vm.set_register(0, sum);
+            vm.heap_mut().push(0);
+            Ok(())
+        }
+        fn fail(_: &mut VM) -> Result<(), VmFault> {
+            Err(VmFault::Native {
+                pc: 0,
+                id: 2,
+                message: "nope".to_string(),
+            })
+        }
+
+        let mut test_vm = VM::new();
+        assert!(test_vm.register_native(1, sum_heap).is_none());
+        test_vm.register_native(2, fail);
+        test_vm.heap = vec![1, 2, 3];
+        test_vm.program = vec![69, 0, 1, 69, 0, 2, 5];
+        test_vm.run();
+        assert_eq!(test_vm.register(0), 6);
+        assert_eq!(test_vm.heap(), &[1, 2, 3, 0]);
+        assert_eq!(test_vm.pc, 6);
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::Native {
+                pc: 3,
+                id: 2,
+                message: "nope".to_string()
+            })
+        );
+
+        test_vm.program = vec![69, 0, 3];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(
+            test_vm.fault(),
+            Some(&VmFault::UnknownNative { pc: 0, id: 3 })
+        );
+    }
+
+    #[test]
+    fn test_load_cmelf() {
+        let mut test_vm = VM::new();
+        let bytes = Cmelf::new(vec![1, 2], vec![0, 0, 1, 244]).to_bytes();
+        assert_eq!(test_vm.load_cmelf(&bytes), Ok(()));
+        assert_eq!(test_vm.ro_data, vec![1, 2]);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 500);
+        assert_eq!(test_vm.load_cmelf(&[0]), Err(CmelfError::TooShort));
+    }
+
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = VM::new();
@@ -0,0 +1,213 @@
+use super::fault::VmFault;
+use super::VM;
+
+/*
+`SYSCALL #n` services. Arguments are passed in $0 and $1, results come back in $0 (and $1)
+
+#0 Exit         $0 = exit code. Stops the VM
+#1 PrintInt     Print $0 as decimal
+#2 PrintChar    Print $0 as a unicode scalar value
+#3 PrintString  Print NUL terminated string at read-only data offset $0
+#4 PrintHeap    Print NUL terminated string at heap address $0
+#5 ReadInt      Read a line as integer into $0. $1 = 1 if a number was read, 0 otherwise
+#6 ReadLine     Read a line into heap buffer at $0 of $1 bytes, NUL terminated. $0 = bytes written, -1 at end of input
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Syscall {
+    Exit,
+    PrintInt,
+    PrintChar,
+    PrintString,
+    PrintHeap,
+    ReadInt,
+    ReadLine,
+}
+
+impl Syscall {
+    pub fn from_number(number: u16) -> Option<Syscall> {
+        let syscall = match number {
+            0 => Syscall::Exit,
+            1 => Syscall::PrintInt,
+            2 => Syscall::PrintChar,
+            3 => Syscall::PrintString,
+            4 => Syscall::PrintHeap,
+            5 => Syscall::ReadInt,
+            6 => Syscall::ReadLine,
+            _ => return None,
+        };
+        Some(syscall)
+    }
+}
+
+impl VM {
+    // Returns true when the VM must stop: Exit or a fault
+    pub(super) fn execute_syscall(&mut self, number: u16, pc: usize) -> bool {
+        let syscall = match Syscall::from_number(number) {
+            Some(syscall) => syscall,
+            None => return self.stop(VmFault::UnknownSyscall { pc, number }),
+        };
+        match syscall {
+            Syscall::Exit => {
+                self.exit_code = Some(self.registers[0]);
+                return true;
+            }
+            Syscall::PrintInt => {
+                let text = self.registers[0].to_string();
+                self.io.write(&text);
+            }
+            Syscall::PrintChar => {
+                let c = char::from_u32(self.registers[0] as u32).unwrap_or('\u{FFFD}');
+                self.io.write(c.encode_utf8(&mut [0; 4]));
+            }
+            Syscall::PrintString | Syscall::PrintHeap => {
+                let address = self.registers[0] as usize;
+                let memory = match syscall {
+                    Syscall::PrintString => &self.ro_data,
+                    _ => &self.heap,
+                };
+                let text = match memory.get(address..) {
+                    Some(bytes) => {
+                        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                        String::from_utf8_lossy(&bytes[..end]).into_owned()
+                    }
+                    None => return self.stop(VmFault::InvalidAddress { pc, address }),
+                };
+                self.io.write(&text);
+            }
+            Syscall::ReadInt => {
+                let value = self
+                    .io
+                    .read_line()
+                    .and_then(|line| line.trim().parse::<i32>().ok());
+                self.registers[0] = value.unwrap_or(0);
+                self.registers[1] = value.is_some() as i32;
+            }
+            Syscall::ReadLine => {
+                let capacity = self.registers[1].max(0) as usize;
+                let address = usize::try_from(self.registers[0]).ok();
+                let end = address.and_then(|address| address.checked_add(capacity));
+                let address = match (address, end) {
+                    (Some(address), Some(end)) if capacity > 0 && end <= self.heap.len() => address,
+                    _ => {
+                        return self.stop(VmFault::InvalidAddress {
+                            pc,
+                            address: end.unwrap_or(self.registers[0] as usize),
+                        })
+                    }
+                };
+                match self.io.read_line() {
+                    Some(line) => {
+                        // Truncated to fit the buffer with its NUL
+                        let length = line.len().min(capacity - 1);
+                        self.heap[address..address + length]
+                            .copy_from_slice(&line.as_bytes()[..length]);
+                        self.heap[address + length] = 0;
+                        self.registers[0] = length as i32;
+                    }
+                    None => self.registers[0] = -1,
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::io::BufferedIo;
+
+    fn vm_with_io(input: &str) -> (VM, BufferedIo) {
+        let io = BufferedIo::new(input);
+        let mut vm = VM::new();
+        vm.set_io(Box::new(io.clone()));
+        (vm, io)
+    }
+
+    #[test]
+    fn test_print_syscalls() {
+        let (mut vm, io) = vm_with_io("");
+        vm.ro_data = b"Hi\0".to_vec();
+        vm.registers[0] = -42;
+        vm.program = vec![66, 0, 1, 66, 0, 3];
+        vm.run_once();
+        vm.registers[0] = 0;
+        vm.run_once();
+        vm.registers[0] = '!' as i32;
+        vm.program.extend_from_slice(&[66, 0, 2]);
+        vm.run_once();
+        assert_eq!(io.output(), "-42Hi!");
+    }
+
+    #[test]
+    fn test_read_syscalls() {
+        let (mut vm, io) = vm_with_io("12\nnope\nhello world");
+        vm.program = vec![66, 0, 5];
+        vm.run();
+        assert_eq!(vm.registers[..2], [12, 1]);
+        vm.pc = 0;
+        vm.run();
+        assert_eq!(vm.registers[..2], [0, 0]);
+
+        vm.heap = vec![0xFF; 8];
+        vm.registers[0] = 1;
+        vm.registers[1] = 6;
+        vm.program = vec![66, 0, 6, 66, 0, 4];
+        vm.pc = 0;
+        vm.run_once();
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(&vm.heap[1..7], b"hello\0");
+        vm.registers[0] = 1;
+        vm.run_once();
+        assert_eq!(io.output(), "hello");
+    }
+
+    #[test]
+    fn test_exit_and_faults() {
+        let (mut vm, _) = vm_with_io("");
+        vm.registers[0] = 3;
+        vm.program = vec![66, 0, 0, 66, 0, 1];
+        vm.run();
+        assert_eq!(vm.exit_code(), Some(3));
+        assert_eq!(vm.pc, 3);
+
+        vm.program = vec![66, 0, 99];
+        vm.pc = 0;
+        vm.run();
+        assert_eq!(vm.exit_code(), None);
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::UnknownSyscall { pc: 0, number: 99 })
+        );
+
+        vm.registers[0] = 10;
+        vm.program = vec![66, 0, 4];
+        vm.pc = 0;
+        vm.run();
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::InvalidAddress { pc: 0, address: 10 })
+        );
+
+        // ReadLine into a buffer before the heap or past the address space
+        vm.heap = vec![0; 8];
+        vm.registers[0] = -1;
+        vm.registers[1] = 4;
+        vm.program = vec![66, 0, 6];
+        vm.pc = 0;
+        vm.run();
+        assert!(matches!(
+            vm.fault(),
+            Some(&VmFault::InvalidAddress { pc: 0, .. })
+        ));
+        vm.registers[0] = 4;
+        vm.registers[1] = i32::MAX;
+        vm.pc = 0;
+        vm.run();
+        assert!(matches!(
+            vm.fault(),
+            Some(&VmFault::InvalidAddress { pc: 0, .. })
+        ));
+        assert_eq!(vm.heap, [0; 8]);
+    }
+}
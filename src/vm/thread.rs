@@ -0,0 +1,368 @@
+use super::fault::VmFault;
+use super::flags::Flags;
+use super::VM;
+
+/*
+Green threads: execution contexts sharing one program and one heap.
+
+SPAWN starts a thread at a label with a copy of the spawning thread's registers, YIELD lets the
+next ready thread run (round-robin by id), JOIN waits until a thread halted, RECV on an empty
+channel until another thread or VM sent a message. With `VmConfig::thread_time_slice` a running
+thread is also switched out after that many instructions, otherwise threads only switch at
+YIELD, JOIN and HLT. With `VmConfig::interleave_seed` time slices have pseudo-random lengths
+from a xorshift generator seeded on load, so a race found with one seed replays exactly.
+
+Each thread has its own stack of up to `VM::STACK_SIZE` values for PUSH/POP; a new thread starts
+with an empty one. The running thread's context lives in the VM fields, the others are saved in
+`VM::threads`. Thread 0 is the main thread: its HLT (or the end of the program) stops the VM,
+the other threads only end themselves. If every thread waits and one of them is in RECV, the VM
+is blocked instead of deadlocked: another VM may still send. Single-threaded programs never
+touch the thread table
+*/
+// Longest time slice of `VmConfig::interleave_seed` without `thread_time_slice`
+const INTERLEAVE_SLICE: u64 = 4;
+
+// Generator state for `seed`, never 0
+pub(super) fn interleave_state(seed: Option<u64>) -> u64 {
+    (seed.unwrap_or(0) ^ 0x9E37_79B9_7F4A_7C15).max(1)
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Context {
+    registers: [i32; VM::REGISTERS],
+    float_registers: [f64; VM::FLOAT_REGISTERS],
+    pc: usize,
+    remainder: i32,
+    flags: Flags,
+    stack: Vec<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum ThreadState {
+    Running,
+    Ready,
+    Joining(usize), // Blocked until that thread finished
+    Receiving(u16), // Blocked in RECV until the channel has a message
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Thread {
+    context: Context, // Stale while the thread is running
+    state: ThreadState,
+}
+
+impl VM {
+    /// Id of the running green thread, 0 for the main thread
+    pub fn thread_id(&self) -> usize {
+        self.thread
+    }
+
+    /// Threads started since the program was loaded, main thread included
+    pub fn thread_count(&self) -> usize {
+        self.threads.len().max(1)
+    }
+
+    // Integer registers and stack values of every thread that can still run, roots of the
+    // collector
+    pub(super) fn thread_roots(&self) -> Vec<i32> {
+        let saved = self
+            .threads
+            .iter()
+            .enumerate()
+            .filter(|&(id, thread)| id != self.thread && thread.state != ThreadState::Finished)
+            .flat_map(|(_, thread)| thread.context.registers.iter().chain(&thread.context.stack));
+        self.registers
+            .iter()
+            .chain(&self.stack)
+            .chain(saved)
+            .copied()
+            .collect()
+    }
+
+    // Everything but the stack, which moves instead of being copied
+    fn context(&self, stack: Vec<i32>) -> Context {
+        Context {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pc: self.pc,
+            remainder: self.remainder,
+            flags: self.flags,
+            stack,
+        }
+    }
+
+    fn restore(&mut self, context: Context) {
+        self.registers = context.registers;
+        self.float_registers = context.float_registers;
+        self.pc = context.pc;
+        self.remainder = context.remainder;
+        self.flags = context.flags;
+        self.stack = context.stack;
+    }
+
+    // Next preemption point, never when cooperative or single-threaded
+    fn schedule_preemption(&mut self) {
+        let slice = match (self.config.thread_time_slice, self.config.interleave_seed) {
+            _ if self.threads.len() < 2 => None,
+            (slice, Some(_)) => {
+                Some(1 + self.next_random() % slice.unwrap_or(INTERLEAVE_SLICE).max(1))
+            }
+            (slice, None) => slice.map(|slice| slice.max(1)),
+        };
+        self.preempt_at = slice.map_or(u64::MAX, |slice| self.executed + slice);
+    }
+
+    // xorshift64*
+    fn next_random(&mut self) -> u64 {
+        self.interleave ^= self.interleave >> 12;
+        self.interleave ^= self.interleave << 25;
+        self.interleave ^= self.interleave >> 27;
+        self.interleave.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Returns the new thread's id
+    pub(super) fn spawn_thread(&mut self, target: usize) -> usize {
+        if self.threads.is_empty() {
+            self.threads.push(Thread {
+                context: Context::default(),
+                state: ThreadState::Running,
+            });
+        }
+        let context = Context {
+            pc: target,
+            remainder: 0,
+            flags: Flags::default(),
+            ..self.context(vec![])
+        };
+        self.threads.push(Thread {
+            context,
+            state: ThreadState::Ready,
+        });
+        if self.preempt_at == u64::MAX {
+            self.schedule_preemption();
+        }
+        self.threads.len() - 1
+    }
+
+    // Saves the running thread and restores the next runnable one, which is the running
+    // thread again if no other can run. Stops the VM if none can, blocked or deadlocked
+    pub(super) fn switch_thread(&mut self, instruction_pc: usize) -> bool /* Is done */ {
+        if self.threads.is_empty() {
+            return false;
+        }
+        let current = self.thread;
+        let stack = std::mem::take(&mut self.stack);
+        self.threads[current].context = self.context(stack);
+        if self.threads[current].state == ThreadState::Running {
+            self.threads[current].state = ThreadState::Ready;
+        }
+        let count = self.threads.len();
+        for id in (1..=count).map(|offset| (current + offset) % count) {
+            let runnable = match self.threads[id].state {
+                ThreadState::Ready => true,
+                ThreadState::Joining(thread) => self.threads[thread].state == ThreadState::Finished,
+                ThreadState::Receiving(channel) => self.has_message(channel),
+                ThreadState::Running | ThreadState::Finished => false,
+            };
+            if runnable {
+                self.resume_thread(id);
+                return false;
+            }
+        }
+        // Resumed in RECV, which retries when the VM runs again
+        let receiving = (1..=count)
+            .map(|offset| (current + offset) % count)
+            .find(|&id| matches!(self.threads[id].state, ThreadState::Receiving(_)));
+        match receiving {
+            Some(id) => {
+                self.resume_thread(id);
+                self.blocked = true;
+                true
+            }
+            None => self.stop(VmFault::Deadlock { pc: instruction_pc }),
+        }
+    }
+
+    fn resume_thread(&mut self, id: usize) {
+        self.threads[id].state = ThreadState::Running;
+        self.thread = id;
+        let context = std::mem::take(&mut self.threads[id].context);
+        self.restore(context);
+        self.schedule_preemption();
+    }
+
+    // RECV on an empty channel
+    pub(super) fn wait_for_message(&mut self, channel: u16, instruction_pc: usize) -> bool {
+        if self.threads.is_empty() {
+            self.blocked = true;
+            return true;
+        }
+        self.threads[self.thread].state = ThreadState::Receiving(channel);
+        self.switch_thread(instruction_pc)
+    }
+
+    // HLT or the end of the program
+    pub(super) fn finish_thread(&mut self, instruction_pc: usize) -> bool /* Is done */ {
+        if self.thread == 0 {
+            return true;
+        }
+        self.threads[self.thread].state = ThreadState::Finished;
+        self.switch_thread(instruction_pc)
+    }
+
+    pub(super) fn join_thread(&mut self, thread: i32, instruction_pc: usize) -> bool /* Is done */ {
+        let id = match usize::try_from(thread) {
+            Ok(id) if id < self.threads.len() => id,
+            _ => {
+                return self.stop(VmFault::UnknownThread {
+                    pc: instruction_pc,
+                    thread,
+                })
+            }
+        };
+        if id == self.thread {
+            return self.stop(VmFault::Deadlock { pc: instruction_pc });
+        }
+        if self.threads[id].state == ThreadState::Finished {
+            return false;
+        }
+        self.threads[self.thread].state = ThreadState::Joining(id);
+        self.switch_thread(instruction_pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::tests::assembled_vm;
+    use crate::vm::{fault::VmFault, RunState, VmConfig, VM};
+
+    // Two workers add to heap byte 0 and 1, main joins both and sums them in $3
+    const WORKERS: &str = "
+        load $0 #2
+        aloc $0
+        load $0 #0
+        spawn $4 @worker
+        load $0 #1
+        spawn $5 @worker
+        join $4
+        join $5
+        load $0 #0
+        ldb $0 $3
+        load $0 #1
+        ldb $0 $1
+        add $3 $1 $3
+        hlt
+        worker: load $1 #0
+        load $2 #20
+        loop: inc $1
+        stb $1 $0
+        yield
+        jlt $1 $2 @loop
+        hlt
+    ";
+
+    #[test]
+    fn test_spawn_yield_join() {
+        let mut vm = assembled_vm(VmConfig::default(), WORKERS);
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        assert_eq!(vm.register(3), 40);
+        assert_eq!((vm.register(4), vm.register(5)), (1, 2));
+        assert_eq!(vm.thread_id(), 0);
+        assert_eq!(vm.thread_count(), 3);
+    }
+
+    #[test]
+    fn test_preemption_interleaves_threads() {
+        // Main busy-waits for a heap byte set by the worker, it never yields
+        let source = "
+            load $0 #1
+            aloc $0
+            load $0 #0
+            spawn $4 @worker
+            wait: ldb $0 $1
+            load $2 #0
+            jgtq $2 $1 @wait
+            join $4
+            hlt
+            worker: load $1 #1
+            stb $1 $0
+            hlt
+        ";
+        let mut cooperative = assembled_vm(VmConfig::default(), source);
+        assert_eq!(cooperative.run_for(1000), RunState::OutOfFuel);
+        assert_eq!(cooperative.heap()[0], 0);
+
+        let config = VmConfig {
+            thread_time_slice: Some(10),
+            ..VmConfig::default()
+        };
+        let mut vm = assembled_vm(config, source);
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        assert_eq!(vm.heap()[0], 1);
+        assert_eq!((vm.thread_id(), vm.register(1)), (0, 1));
+    }
+
+    #[test]
+    fn test_threads_have_own_stacks() {
+        // Both threads push, yield to each other, then pop their own values
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #1
+            push $0
+            spawn $4 @worker
+            load $0 #2
+            push $0
+            yield
+            pop $1
+            pop $2
+            join $4
+            hlt
+            worker: load $0 #7
+            push $0
+            yield
+            pop $3
+            hlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), None);
+        assert_eq!((vm.register(1), vm.register(2)), (2, 1));
+        assert_eq!(vm.register(3), 0); // Set in the worker's registers only
+        assert!(vm.stack().is_empty());
+
+        let mut vm = assembled_vm(VmConfig::default(), "pop $0");
+        vm.run();
+        assert_eq!(vm.fault(), Some(&VmFault::StackUnderflow { pc: 0 }));
+        // The worker's stack starts empty
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "push $0\nspawn $4 @worker\njoin $4\nhlt\nworker: pop $0\nhlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), Some(&VmFault::StackUnderflow { pc: 9 }));
+
+        let mut vm = assembled_vm(VmConfig::default(), "load $1 @loop\nloop: push $0\njmp $1");
+        vm.run();
+        assert_eq!(vm.stack().len(), VM::STACK_SIZE);
+        assert_eq!(vm.fault(), Some(&VmFault::StackOverflow { pc: 4 }));
+    }
+
+    #[test]
+    fn test_join_faults() {
+        let mut vm = assembled_vm(VmConfig::default(), "load $0 #7\njoin $0\nhlt");
+        vm.run();
+        assert_eq!(
+            vm.fault(),
+            Some(&VmFault::UnknownThread { pc: 4, thread: 7 })
+        );
+        // Main waits for the worker, the worker for main
+        let mut vm = assembled_vm(
+            VmConfig::default(),
+            "load $0 #0\nspawn $1 @worker\njoin $1\nhlt\nworker: join $0\nhlt",
+        );
+        vm.run();
+        assert_eq!(vm.fault(), Some(&VmFault::Deadlock { pc: 11 }));
+    }
+}
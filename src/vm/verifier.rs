@@ -0,0 +1,229 @@
+use std::fmt;
+
+use super::instruction::{DecodeError, Instruction, Opcode};
+
+/*
+Static checks of bytecode before it is loaded:
+
+- every opcode byte is a known opcode
+- every instruction is complete, no operand past the end of the program
+- register operands exist in their bank
+- constant jump targets land on an instruction boundary (or the end of the program)
+
+Constant targets are the 16 bits operands of JGT/JLT/JGTQ/JLTQ/SPAWN and the `LOAD $r #target`
+immediately followed by a register jump on `$r`, which is what the assembler emits for labels
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VerifyError {
+    IllegalOpcode { offset: usize, opcode: u8 },
+    TruncatedInstruction { offset: usize, opcode: Opcode },
+    InvalidRegister { offset: usize, register: u8 },
+    InvalidJumpTarget { offset: usize, target: i64 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::IllegalOpcode { offset, opcode } => {
+                write!(f, "Illegal opcode {} at byte {}", opcode, offset)
+            }
+            VerifyError::TruncatedInstruction { offset, opcode } => {
+                write!(
+                    f,
+                    "Instruction {:?} at byte {} is truncated",
+                    opcode, offset
+                )
+            }
+            VerifyError::InvalidRegister { offset, register } => {
+                write!(
+                    f,
+                    "Register ${} does not exist, at byte {}",
+                    register, offset
+                )
+            }
+            VerifyError::InvalidJumpTarget { offset, target } => write!(
+                f,
+                "Jump at byte {} lands at {}, not on an instruction",
+                offset, target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// Instruction at `offset`, `None` if one of its registers does not exist
+type Decoded = (usize, Option<Instruction>);
+
+/// Checks `program` without running it. All problems are reported, not only the first
+pub fn verify(program: &[u8]) -> Result<(), Vec<VerifyError>> {
+    let mut errors = vec![];
+    let instructions = decode(program, &mut errors);
+    let is_boundary = |target: i64| {
+        target == program.len() as i64
+            || instructions
+                .binary_search_by_key(&target, |&(offset, _)| offset as i64)
+                .is_ok()
+    };
+
+    let mut previous: Option<&Instruction> = None;
+    for (offset, instruction) in &instructions {
+        let instruction = match instruction {
+            Some(instruction) => instruction,
+            None => {
+                previous = None;
+                continue;
+            }
+        };
+        let next = (offset + instruction.byte_len()) as i64;
+        let target = match instruction.opcode {
+            Opcode::JGT | Opcode::JLT | Opcode::JGTQ | Opcode::JLTQ | Opcode::SPAWN => {
+                Some(instruction.immediate as i64)
+            }
+            opcode => constant_register(previous, instruction).and_then(|value| match opcode {
+                Opcode::JMP
+                | Opcode::JEQ
+                | Opcode::JNEQ
+                | Opcode::JZ
+                | Opcode::JNZ
+                | Opcode::JN
+                | Opcode::JC
+                | Opcode::JO => Some(value),
+                Opcode::JMPF | Opcode::JEQF | Opcode::JNEQF => Some(next + value),
+                Opcode::JMPB | Opcode::JEQB | Opcode::JNEQB => Some(next - value),
+                _ => None,
+            }),
+        };
+        if let Some(target) = target {
+            if !is_boundary(target) {
+                errors.push(VerifyError::InvalidJumpTarget {
+                    offset: *offset,
+                    target,
+                });
+            }
+        }
+        previous = Some(instruction);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Linear sweep. Stops at the first byte that is not an opcode, its length is unknown
+fn decode(program: &[u8], errors: &mut Vec<VerifyError>) -> Vec<Decoded> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < program.len() {
+        let opcode = Opcode::from(program[offset]);
+        if opcode == Opcode::IGL {
+            errors.push(VerifyError::IllegalOpcode {
+                offset,
+                opcode: program[offset],
+            });
+            break;
+        }
+        match Instruction::decode(program, offset) {
+            Ok(instruction) => instructions.push((offset, Some(instruction))),
+            Err(DecodeError::InvalidRegister(register)) => {
+                errors.push(VerifyError::InvalidRegister { offset, register });
+                instructions.push((offset, None));
+            }
+            Err(DecodeError::Truncated) => {
+                errors.push(VerifyError::TruncatedInstruction { offset, opcode });
+                break;
+            }
+        }
+        offset += opcode.byte_len();
+    }
+    instructions
+}
+
+// Value of the register operand of `jump` when the previous instruction is `LOAD` into it
+fn constant_register(previous: Option<&Instruction>, jump: &Instruction) -> Option<i64> {
+    let load = previous.filter(|p| p.opcode == Opcode::LOAD)?;
+    (load.registers[0] == jump.registers[0]).then_some(load.immediate as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{Operand, ProgramBuilder};
+
+    #[test]
+    fn test_verify_valid_program() {
+        let program = ProgramBuilder::new()
+            .load(1, 3)
+            .label("loop")
+            .inc(0)
+            .jlt(0, 1, "loop")
+            .jeq_label(5, "end")
+            .load(4, 0)
+            .emit(Opcode::JMPF, &[Operand::Register(4)])
+            .label("end")
+            .hlt()
+            .build()
+            .unwrap();
+        assert_eq!(verify(&program), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let program = vec![
+            22, 0, 9, 0, 0, // JGT $0 $9
+            22, 0, 1, 0, 3, // JGT $0 $1 into the middle of the first JGT
+            0, 1, 0, 11, // LOAD $1 #11
+            6, 1, // JMP $1, byte 11 is inside LOAD
+            0, 0, // truncated LOAD
+        ];
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                VerifyError::InvalidRegister {
+                    offset: 0,
+                    register: 9
+                },
+                VerifyError::TruncatedInstruction {
+                    offset: 16,
+                    opcode: Opcode::LOAD
+                },
+                VerifyError::InvalidJumpTarget {
+                    offset: 5,
+                    target: 3
+                },
+                VerifyError::InvalidJumpTarget {
+                    offset: 14,
+                    target: 11
+                },
+            ])
+        );
+        assert_eq!(
+            verify(&[5, 200, 5]),
+            Err(vec![VerifyError::IllegalOpcode {
+                offset: 1,
+                opcode: 200
+            }])
+        );
+    }
+
+    #[test]
+    fn test_verify_assembler_output() {
+        let source = "
+            .macro jump_if_eq a b target
+            eq \\a \\b $5
+            load $5 \\target
+            jeq $5
+            .endm
+            start: inc $0
+            load $4 #(end - after)
+            jmpf $4
+            after: jump_if_eq $0 $1 @start
+            end: hlt
+        ";
+        let image = crate::assembler::Assembler::new().assemble(source).unwrap();
+        assert_eq!(verify(&image[crate::cmelf::CMELF_HEADER_LENGTH..]), Ok(()));
+    }
+}